@@ -0,0 +1,105 @@
+// Local JSON control API, lets editors, git hooks or scripts drive the tool without a terminal.
+//
+// GET  /status        state of every builder
+// GET  /clients       connected hot-patch clients
+// GET  /diagnostics   last error of every builder that failed, with the messages of rustc
+// GET  /patches       recently sent patches
+// GET  /protocol      devtools and subsecond-types versions patches are serialized with
+// POST /build/thin    same as "r"
+// POST /build/fat     fat build without restarting the binary
+// POST /build/rebuild same as "R"
+// POST /build/rollback undo the last patch
+
+use std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::{Arc, mpsc::Sender},
+};
+
+use serde::Serialize;
+
+use crate::{
+    builder::BuildCommand,
+    http::{HttpHead, write_response},
+    protocol::protocol_info,
+    status::DevState,
+};
+
+pub struct ApiServer {
+    addr: String,
+    command_tx: Sender<BuildCommand>,
+    clear_patches_tx: Sender<()>,
+    dev_state: Arc<DevState>,
+}
+
+impl ApiServer {
+    pub fn new(
+        addr: &str,
+        command_tx: Sender<BuildCommand>,
+        clear_patches_tx: Sender<()>,
+        dev_state: Arc<DevState>,
+    ) -> Self {
+        Self {
+            addr: addr.to_string(),
+            command_tx,
+            clear_patches_tx,
+            dev_state,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let listener = TcpListener::bind(&self.addr).unwrap();
+        tracing::info!("Control API listening on http://{}", self.addr);
+        // requests are tiny, no need for a thread per connection
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = self.handle_connection(stream) {
+                tracing::trace!("Control API connection error: {err}");
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let Some(request) = HttpHead::read(&mut BufReader::new(stream)) else {
+            return Ok(());
+        };
+
+        let (status, body) = match (request.method(), request.path()) {
+            ("GET", "/status") => ok(&self.dev_state.builders()),
+            ("GET", "/clients") => ok(&self.dev_state.clients()),
+            ("GET", "/diagnostics") => ok(&self.dev_state.diagnostics()),
+            ("GET", "/patches") => ok(&self.dev_state.patches()),
+            ("GET", "/protocol") => ok(&protocol_info()),
+            ("POST", "/build/thin") => self.queue(BuildCommand::Thin),
+            ("POST", "/build/fat") => self.queue(BuildCommand::Fat),
+            ("POST", "/build/rebuild") => {
+                _ = self.clear_patches_tx.send(());
+                self.queue(BuildCommand::FatRebuild)
+            }
+            ("POST", "/build/rollback") => self.queue(BuildCommand::Rollback),
+            _ => (
+                "404 Not Found",
+                serde_json::json!({ "error": "not found" }).to_string(),
+            ),
+        };
+
+        write_response(&mut writer, status, "application/json", body.as_bytes())
+    }
+
+    fn queue(&self, command: BuildCommand) -> (&'static str, String) {
+        match self.command_tx.send(command) {
+            Ok(_) => (
+                "202 Accepted",
+                serde_json::json!({ "queued": command }).to_string(),
+            ),
+            Err(_) => (
+                "503 Service Unavailable",
+                serde_json::json!({ "error": "builder is not running" }).to_string(),
+            ),
+        }
+    }
+}
+
+fn ok(value: &impl Serialize) -> (&'static str, String) {
+    ("200 OK", serde_json::to_string(value).unwrap())
+}
@@ -1,148 +1,460 @@
 use std::{
     path::{Path, PathBuf},
-    process::{Child, Command},
-    sync::{Arc, atomic::AtomicU64, mpsc::Receiver},
-    time::Instant,
+    sync::{
+        Arc,
+        atomic::AtomicU64,
+        mpsc::{Receiver, Sender},
+    },
+    time::{Duration, Instant},
 };
 
-use dioxus_devtools::DevserverMsg;
+use anyhow::{Context as _, bail};
+use dioxus_devtools::{DevserverMsg, HotReloadMsg};
 use multiqueue::BroadcastSender;
 
 use crate::{
     RustcArgs,
+    cancel::{CancelToken, Cancelled},
+    classify::{FatReason, Snapshot},
     context::Context,
-    patch::{HotpatchModuleCache, create_jump_table},
+    fingerprint::{SentObjects, build_id},
+    gc,
+    patch::{HotpatchModuleCache, PatchError, create_jump_table},
+    process::{ProcessSpec, RestartPolicy, ServerProcess, wait_for_port_free},
+    protocol::{AppVersions, Handshake},
+    proxy::SiteState,
+    queue::BuildQueue,
+    report,
+    status::{BuildPhase, BuilderHandle, DevState},
     thin,
+    wasi::WasiRuntime,
+    wbg_shim,
+    workspace::Members,
 };
 
+const PORT_FREE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ordered by priority, when several builds are queued only the highest one is run, rollbacks are
+/// queued separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BuildCommand {
+    /// Re-send the patch preceding the last one
+    Rollback,
     Thin,
     Fat,
     FatRebuild,
 }
 
+impl BuildCommand {
+    /// Whether the result of a running `other` build is useless once `self` is requested
+    pub fn supersedes(self, other: BuildCommand) -> bool {
+        match self {
+            BuildCommand::Rollback => false,
+            BuildCommand::Thin => other <= BuildCommand::Thin,
+            _ => self >= other,
+        }
+    }
+}
+
 pub struct Builder {
     pub ctx: Context,
     cache: Arc<HotpatchModuleCache>,
     rustc_args: RustcArgs,
+    /// Workspace members thin builds recompile, see [`crate::workspace`]
+    members: Members,
+    /// Inputs at the last fat build, see [`crate::classify`]
+    snapshot: Snapshot,
+    /// Fingerprint of the objects clients run, see [`crate::fingerprint`]
+    objects: SentObjects,
     patch_sender: BroadcastSender<DevserverMsg>,
-    pid: Option<u32>,
+    /// Tells the hot-patch server to forget the patches it replays to new clients
+    clear_patches_tx: Sender<()>,
     aslr_reference: Arc<AtomicU64>,
-    running_binary: Option<Child>,
-    command_receiver: Receiver<BuildCommand>,
+    running_binary: Option<ServerProcess>,
+    site_state: Arc<SiteState>,
+    status: BuilderHandle,
+    /// Patches sent since the last fat build, used for rollbacks
+    sent_patches: Vec<HotReloadMsg>,
+    /// Id of the last fat build, patches are stamped with it
+    build_id: Option<u64>,
 }
 
 impl Builder {
     pub fn new(
         ctx: Context,
         patch_sender: BroadcastSender<DevserverMsg>,
+        clear_patches_tx: Sender<()>,
         aslr_reference: Arc<AtomicU64>,
-        command_receiver: Receiver<BuildCommand>,
+        site_state: Arc<SiteState>,
+        dev_state: &Arc<DevState>,
     ) -> Self {
+        let status = dev_state.register_builder(ctx.final_binary_name(), ctx.triple.to_string());
         Self {
             ctx,
             cache: Arc::new(HotpatchModuleCache::default()),
             rustc_args: RustcArgs::default(),
+            members: Members::default(),
+            snapshot: Snapshot::default(),
+            objects: SentObjects::default(),
             patch_sender,
-            pid: None,
+            clear_patches_tx,
             aslr_reference,
             running_binary: None,
-            command_receiver,
+            site_state,
+            status,
+            sent_patches: vec![],
+            build_id: None,
         }
     }
 
-    pub fn run(&mut self) {
-        while let Ok(command) = self.command_receiver.recv() {
+    pub fn run(&mut self, command_receiver: Receiver<BuildCommand>) {
+        let queue = BuildQueue::spawn(command_receiver);
+        while let Some((command, cancel)) = queue.next() {
             match command {
+                BuildCommand::Rollback => {
+                    self.rollback();
+                }
                 BuildCommand::Thin => {
-                    self.build_thin();
+                    self.build_thin(&cancel);
                 }
                 BuildCommand::Fat => {
-                    self.build_fat();
+                    self.build_fat(&cancel);
                 }
                 BuildCommand::FatRebuild => {
-                    self.rebuild_fat();
+                    self.rebuild_fat(&cancel);
                 }
             }
+            queue.finish();
         }
     }
 
     pub fn kill_child_if_running(&mut self) {
-        if let Some(process) = &mut self.running_binary {
-            match process.kill() {
-                Ok(_) => tracing::debug!("Killed executable successfully"),
-                Err(_) => tracing::error!("Couldn't kill running executable!"),
-            }
-            self.running_binary = None;
+        if let Some(mut process) = self.running_binary.take() {
+            process.stop();
+            tracing::debug!("Stopped running executable");
         }
     }
 
-    pub fn rebuild_fat(&mut self) {
+    pub fn rebuild_fat(&mut self, cancel: &CancelToken) {
         self.kill_child_if_running();
 
-        self.build_fat();
+        self.build_fat(cancel);
     }
 
-    pub fn build_fat(&mut self) {
-        let path = crate::fat::build_fat(&self.ctx);
+    pub fn build_fat(&mut self, cancel: &CancelToken) {
+        // the proxy shows the restarting page until the guard is dropped, whichever way this returns
+        let _rebuild = RebuildGuard::start(&self.site_state);
+        self.status.set_phase(BuildPhase::Compiling);
+        let path = match self.load_fat_build(cancel) {
+            Ok(path) => path,
+            Err(err) => {
+                if err.is::<Cancelled>() {
+                    tracing::info!("Fat build cancelled");
+                    self.status.cancelled(BuildCommand::Fat);
+                } else {
+                    tracing::error!("Fat build failed: {err:#}");
+                    self.status.failed(BuildCommand::Fat, &err);
+                }
+                return;
+            }
+        };
 
-        self.rustc_args = serde_json::from_str(
-            &std::fs::read_to_string(self.ctx.rustc_wrapper_file.path()).unwrap(),
-        )
-        .unwrap();
+        self.build_id = match build_id(&path) {
+            Ok(build_id) => {
+                tracing::debug!("Fat build id {build_id}");
+                self.status.set_build_id(build_id);
+                if self.ctx.is_web() {
+                    self.site_state.set_client_build_id(build_id);
+                }
+                Some(build_id)
+            }
+            Err(err) => {
+                tracing::warn!("Couldn't compute build id of {}: {err}", path.display());
+                None
+            }
+        };
+        let app_versions = AppVersions::from_lockfile(&self.ctx.working_dir);
+        if let Some(reason) = Handshake::default()
+            .or_app_versions(&app_versions)
+            .incompatibility()
+        {
+            tracing::error!("Hot-patch clients will be refused, {reason}");
+        }
+        self.status.dev_state().set_app_versions(app_versions);
 
-        self.cache = Arc::new(HotpatchModuleCache::new(&path, &self.ctx.triple).unwrap());
+        // only one of the tables is filled, depending on the platform
+        let fat_symbols = self.cache.symbol_table.len() + self.cache.symbol_ifunc_map.len();
+        self.status.metrics(|m| m.fat_symbols = Some(fat_symbols));
 
         self.run_if_native(&path);
+        self.status.succeeded(BuildCommand::Fat);
+    }
+
+    /// Runs the fat build and loads what thin builds need from it, returns the built executable
+    fn load_fat_build(&mut self, cancel: &CancelToken) -> anyhow::Result<PathBuf> {
+        // before cargo reads anything, edits made during the build have to show up as changes
+        let mut snapshot = Snapshot::record(&self.ctx);
+        let artifacts = crate::fat::build_fat(&self.ctx, cancel, &self.status)?;
+        let path = artifacts.exe;
+        self.objects.reset(artifacts.objects_fingerprint);
+        self.sent_patches.clear();
+        gc::remove_patches(&self.ctx);
+
+        self.rustc_args = serde_json::from_str(&std::fs::read_to_string(
+            self.ctx.rustc_wrapper_file.path(),
+        )?)
+        .context("couldn't read the rustc args of the fat build")?;
+
+        self.members = Members::load(&self.ctx);
+        snapshot.add_build_script_inputs(&self.ctx);
+        self.snapshot = snapshot;
+        self.cache = Arc::new(HotpatchModuleCache::new(&path, &self.ctx.triple)?);
+        Ok(path)
+    }
+
+    pub fn build_thin(&mut self, cancel: &CancelToken) {
+        // nothing can load a patch into a running WASI module, see `wasi`
+        if self.ctx.is_wasi() {
+            tracing::info!("Rebuilding and relaunching the WASI module");
+            self.rebuild_fat(cancel);
+            return;
+        }
+        if let Some(reason) = self.fat_reason() {
+            tracing::info!("Starting a fat rebuild, {reason}");
+            self.clear_patches();
+            self.rebuild_fat(cancel);
+            return;
+        }
+        match self.prepare_thin(cancel) {
+            Ok(Some(msg)) => self.send_patch(msg),
+            Ok(None) => tracing::info!("No code changes, nothing to patch"),
+            Err(err) if err.is::<Cancelled>() => tracing::info!("Thin build cancelled"),
+            Err(err) => {
+                tracing::error!("Thin build failed: {err:#}");
+                if self.rebuilds_fat_on(&err) {
+                    tracing::info!("Starting a fat rebuild to resolve the missing symbols");
+                    self.clear_patches();
+                    self.rebuild_fat(cancel);
+                }
+            }
+        }
     }
 
-    pub fn build_thin(&self) {
+    /// Whether `err` is only fixed by a fat rebuild and the builder is configured to start it
+    pub fn rebuilds_fat_on(&self, err: &anyhow::Error) -> bool {
+        self.ctx.fat_rebuild_on_unresolved
+            && err
+                .downcast_ref::<PatchError>()
+                .is_some_and(PatchError::needs_fat_rebuild)
+    }
+
+    /// Same as the manual rebuild: patches of the old fat build must not be replayed to new clients
+    pub fn clear_patches(&self) {
+        _ = self.clear_patches_tx.send(());
+    }
+
+    /// Change since the last fat build a patch can't carry
+    pub fn fat_reason(&self) -> Option<FatReason> {
+        self.snapshot.fat_reason(&self.ctx)
+    }
+
+    /// Builds and links a patch and creates its jump table without sending it to clients.
+    /// Returns `None` if the compiled code is the same as in the previous build.
+    pub fn prepare_thin(&mut self, cancel: &CancelToken) -> anyhow::Result<Option<HotReloadMsg>> {
+        self.status.set_phase(BuildPhase::Compiling);
+        let res = self.create_patch(cancel);
+        match &res {
+            // the build continues with sending the patch
+            Ok(Some(_)) => {}
+            Ok(None) => self.status.succeeded(BuildCommand::Thin),
+            Err(err) if err.is::<Cancelled>() => self.status.cancelled(BuildCommand::Thin),
+            Err(err) => self.status.failed(BuildCommand::Thin, err),
+        }
+        res
+    }
+
+    fn create_patch(&mut self, cancel: &CancelToken) -> anyhow::Result<Option<HotReloadMsg>> {
         let aslr_reference = self
             .aslr_reference
             .load(std::sync::atomic::Ordering::SeqCst);
         if !self.ctx.is_wasm_or_wasi() && aslr_reference == 0 {
-            tracing::error!("Thin build canceled, aslr reference is 0 on non-wasm build!");
-            return;
+            bail!("Thin build canceled, aslr reference is 0 on non-wasm build!");
         }
-        let time_start = thin::build_thin(&self.ctx, &self.rustc_args, aslr_reference, &self.cache);
+        let Some(time_start) = thin::build_thin(
+            &self.ctx,
+            &self.rustc_args,
+            aslr_reference,
+            &self.cache,
+            &mut self.members,
+            &mut self.objects,
+            cancel,
+            &self.status,
+        )?
+        else {
+            return Ok(None);
+        };
 
         let new = self.ctx.patch_exe(time_start);
+        let is_web = self.ctx.is_web();
+        // the jump table rewrites a wasm patch and strips what wasm-bindgen needs to generate a shim
+        let unprocessed = if is_web {
+            Some(std::fs::read(&new)?)
+        } else {
+            None
+        };
+        self.status.set_phase(BuildPhase::JumpTable);
         let now = Instant::now();
-        let mut jump_table = create_jump_table(&new, &self.ctx.triple, &self.cache).unwrap();
+        let mut jump_table = create_jump_table(&new, &self.ctx.triple, &self.cache)
+            .inspect_err(|err| report::keep_error(&self.ctx, time_start, err))?;
+        report::keep_json(&self.ctx, time_start, "jump_table.json", &jump_table);
         tracing::debug!("Created jump table in {}s", now.elapsed().as_secs_f32());
+        let patch_size = std::fs::metadata(&jump_table.lib).map(|m| m.len()).ok();
+        let entries = jump_table.map.len();
+        self.status.metrics(|m| {
+            m.patch_size_bytes = patch_size;
+            m.jump_table_entries = Some(entries);
+        });
 
-        if self.ctx.triple.architecture == target_lexicon::Architecture::Wasm32 {
+        let mut assets = Vec::new();
+        if let Some(unprocessed) = unprocessed {
+            self.status.set_phase(BuildPhase::Bindgen);
+            let app_glue = std::fs::read_to_string(self.ctx.bindgen_glue_path())?;
+            let shim = wbg_shim::write_shim(&unprocessed, &jump_table.lib, &app_glue)
+                .inspect_err(|err| report::keep_error(&self.ctx, time_start, err))?;
+            if let Some(shim) = shim {
+                report::keep_copy(&self.ctx, time_start, &shim);
+                self.ctx.write_thin_wasm_patch_to_pkg(&shim);
+                // the client imports the shim before applying the patch
+                assets.push(self.ctx.pkg_url(shim.file_name().unwrap()));
+            }
+        }
+
+        // a newer build is coming, this patch must never be sent after it
+        cancel.check()?;
+
+        if is_web {
             // Make sure we use the dir relative to the public dir, so the web can load it as a proper URL
             //
             // ie we would've shipped `/Users/foo/Projects/dioxus/target/dx/project/debug/web/public/wasm/lib.wasm`
             //    but we want to ship `/wasm/lib.wasm`
             let patch_lib_name = jump_table.lib.file_name().unwrap();
             self.ctx.write_thin_wasm_patch_to_pkg(&jump_table.lib);
-            jump_table.lib = PathBuf::from("/pkg/").join(patch_lib_name);
+            jump_table.lib = self.ctx.pkg_url(patch_lib_name);
         }
 
-        let msg = DevserverMsg::HotReload(dioxus_devtools::HotReloadMsg {
-            templates: Vec::new(),
-            assets: Vec::new(),
-            ms_elapsed: 0,
-            jump_table: Some(jump_table),
-            for_build_id: None,
-            for_pid: self.pid,
-        });
-        self.patch_sender.try_send(msg).unwrap();
         tracing::info!(
             "Hot-patch created in {}s",
             time_start.elapsed().unwrap().as_secs_f32()
         );
+
+        Ok(Some(HotReloadMsg {
+            templates: Vec::new(),
+            assets,
+            ms_elapsed: 0,
+            jump_table: Some(jump_table),
+            for_build_id: self.build_id,
+            for_pid: self.running_binary.as_ref().map(|p| p.pid()),
+        }))
+    }
+
+    pub fn send_patch(&mut self, msg: HotReloadMsg) {
+        self.status.set_phase(BuildPhase::Sending);
+        self.publish(msg.clone());
+        self.objects.sent();
+        self.sent_patches.push(msg);
+        self.status.succeeded(BuildCommand::Thin);
+    }
+
+    /// Drops a prepared patch that won't be sent, the next thin build has to build it again
+    pub fn discard_patch(&mut self, msg: &HotReloadMsg) {
+        if let Some(jump_table) = &msg.jump_table {
+            gc::remove_patch(&self.ctx, &jump_table.lib);
+        }
+        self.objects.discarded();
+        self.status.cancelled(BuildCommand::Thin);
+    }
+
+    fn publish(&self, msg: HotReloadMsg) {
+        if let Some(jump_table) = &msg.jump_table {
+            self.status.record_patch(
+                jump_table.lib.display().to_string(),
+                msg.for_build_id,
+                msg.for_pid,
+            );
+        }
+        self.patch_sender
+            .try_send(DevserverMsg::HotReload(msg))
+            .unwrap();
+    }
+
+    /// Re-sends the patch before the last one. Patches contain the whole crate, so applying an older
+    /// patch undoes the newer ones. The first patch can only be undone with a fat rebuild.
+    /// Whether a patch precedes the last one, the first patch can only be undone by a fat rebuild
+    pub fn can_rollback(&self) -> bool {
+        self.sent_patches.len() >= 2
+    }
+
+    pub fn rollback(&mut self) -> bool {
+        if !self.can_rollback() {
+            tracing::warn!(
+                "Nothing to roll back to for {}, do a fat rebuild to undo the first patch",
+                self.ctx.final_binary_name()
+            );
+            return false;
+        }
+        self.sent_patches.pop();
+        let mut msg = self.sent_patches.last().unwrap().clone();
+        msg.for_pid = self.running_binary.as_ref().map(|p| p.pid());
+        self.publish(msg);
+        tracing::info!("Rolled back {}", self.ctx.final_binary_name());
+        true
     }
 
+    /// Launches binaries, and WASI modules under their runtime
     pub fn run_if_native(&mut self, path: &Path) {
-        if self.ctx.bin.is_some() {
-            let mut exe_cmd = Command::new(path);
-            exe_cmd.env("LEPTOS_OUTPUT_NAME", &self.ctx.package);
-            let new_exe = exe_cmd.spawn().unwrap();
-            self.pid = Some(new_exe.id());
-            self.running_binary = Some(new_exe);
+        let name = match &self.ctx.bin {
+            Some(bin) => Some(bin.clone()),
+            None if self.ctx.is_wasi() => Some(self.ctx.final_binary_name()),
+            None => None,
+        };
+        if let Some(name) = name {
+            // the previous instance might still be releasing its socket
+            if let Some(addr) = &self.ctx.site_addr {
+                wait_for_port_free(addr, PORT_FREE_TIMEOUT);
+            }
+
+            let mut envs = self.ctx.run_envs();
+            if let Some(build_id) = self.build_id {
+                // `dioxus_cli_config::build_id()` reads the first one
+                for key in ["DIOXUS_BUILD_ID", "SUBSECOND_BUILD_ID"] {
+                    envs.push((key.to_string(), build_id.to_string()));
+                }
+            }
+            let cwd = self.ctx.run_cwd();
+            let (program, args) = if self.ctx.is_wasi() {
+                let runtime = WasiRuntime::new(&self.ctx);
+                let args = runtime.args(path, &cwd, &envs, &self.ctx.run.args);
+                (runtime.program().to_path_buf(), args)
+            } else {
+                (path.to_path_buf(), self.ctx.run.args.clone())
+            };
+            let spec = ProcessSpec {
+                name,
+                program,
+                args,
+                envs,
+                cwd,
+                output: self.status.dev_state(),
+            };
+            let restart_policy = RestartPolicy {
+                enabled: self.ctx.auto_restart,
+                ..Default::default()
+            };
+            match ServerProcess::spawn(spec, restart_policy, self.ctx.stop_grace_period) {
+                Ok(process) => self.running_binary = Some(process),
+                Err(err) => tracing::error!("Couldn't launch {}: {err}", path.display()),
+            }
         }
     }
 }
@@ -152,3 +464,19 @@ impl Drop for Builder {
         self.kill_child_if_running();
     }
 }
+
+/// Keeps the proxy in its restarting state while alive
+struct RebuildGuard(Arc<SiteState>);
+
+impl RebuildGuard {
+    fn start(site_state: &Arc<SiteState>) -> Self {
+        site_state.start_rebuild();
+        Self(Arc::clone(site_state))
+    }
+}
+
+impl Drop for RebuildGuard {
+    fn drop(&mut self) {
+        self.0.finish_rebuild();
+    }
+}
@@ -0,0 +1,172 @@
+// Cancellation of in-flight builds.
+//
+// Every build gets a token, once a newer command supersedes the build the token is cancelled and the
+// rustc/cargo/linker process the build is waiting on gets killed.
+//
+// These processes run in their own process group so the whole tree can be killed, which also keeps
+// them from receiving the terminal's Ctrl+C. The groups still running are killed by `kill_running`
+// when this tool exits.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use thiserror::Error;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Ids of the spawned processes that haven't exited yet, they lead their process groups
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Error)]
+#[error("build was cancelled by a newer request")]
+pub struct Cancelled;
+
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like [`Command::output`] but kills the process when the token is cancelled
+    pub fn output(&self, cmd: &mut Command) -> anyhow::Result<Output> {
+        self.check()?;
+        let mut child = spawn_in_group(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+
+        // pipes have to be drained while waiting, otherwise a chatty process blocks forever
+        let stdout = child.stdout.take().map(read_in_background);
+        let stderr = child.stderr.take().map(read_in_background);
+        let status = self.wait(&mut child)?;
+
+        Ok(Output {
+            status,
+            stdout: stdout.map(|h| h.join().unwrap()).unwrap_or_default(),
+            stderr: stderr.map(|h| h.join().unwrap()).unwrap_or_default(),
+        })
+    }
+
+    /// Like [`Command::status`] but kills the process when the token is cancelled. stdout and
+    /// stderr are piped, every line is passed to `on_line` as soon as it is printed
+    pub fn status_lines(
+        &self,
+        cmd: &mut Command,
+        on_line: impl Fn(&str) + Send + Sync + 'static,
+    ) -> anyhow::Result<ExitStatus> {
+        self.check()?;
+        let mut child = spawn_in_group(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+
+        let on_line = Arc::new(on_line);
+        let readers = [
+            child
+                .stdout
+                .take()
+                .map(|pipe| lines_in_background(pipe, on_line.clone())),
+            child
+                .stderr
+                .take()
+                .map(|pipe| lines_in_background(pipe, on_line.clone())),
+        ];
+        let status = self.wait(&mut child)?;
+        for reader in readers.into_iter().flatten() {
+            _ = reader.join();
+        }
+        Ok(status)
+    }
+
+    fn wait(&self, child: &mut Child) -> anyhow::Result<ExitStatus> {
+        RUNNING.lock().unwrap().push(child.id());
+        let status = self.wait_running(child);
+        RUNNING.lock().unwrap().retain(|id| *id != child.id());
+        status
+    }
+
+    fn wait_running(&self, child: &mut Child) -> anyhow::Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if self.is_cancelled() {
+                kill_group(child);
+                _ = child.wait();
+                return Err(Cancelled.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Kills every build process still running along with its children, called before exiting
+pub fn kill_running() {
+    for id in RUNNING.lock().unwrap().drain(..) {
+        kill_group_of(id);
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+fn lines_in_background(
+    pipe: impl Read + Send + 'static,
+    on_line: Arc<impl Fn(&str) + Send + Sync + 'static>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else { break };
+            on_line(&line);
+        }
+    })
+}
+
+// cargo spawns rustc processes of its own, so the whole process group has to be killed
+#[cfg(unix)]
+fn spawn_in_group(cmd: &mut Command) -> std::io::Result<Child> {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0).spawn()
+}
+
+#[cfg(not(unix))]
+fn spawn_in_group(cmd: &mut Command) -> std::io::Result<Child> {
+    cmd.spawn()
+}
+
+fn kill_group(child: &mut Child) {
+    kill_group_of(child.id());
+    _ = child.kill();
+}
+
+#[cfg(unix)]
+fn kill_group_of(id: u32) {
+    _ = Command::new("kill")
+        .arg("-KILL")
+        .arg(format!("-{id}"))
+        .status();
+}
+
+// without process groups the children share the console and get its Ctrl+C themselves
+#[cfg(not(unix))]
+fn kill_group_of(_id: u32) {}
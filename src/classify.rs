@@ -0,0 +1,302 @@
+// Deciding whether a thin build can apply the pending changes.
+//
+// Right before every fat build the inputs a thin build can't account for are recorded: manifests, the
+// lockfile, cargo config, the toolchain file, build scripts and the files they ask to be rerun on,
+// proc-macro crates of the workspace, and the environment cargo gives rustc, i.e. the env of this
+// process with the env file and the `[env]` tables of the cargo config applied. Recording before
+// cargo starts means edits made while the fat build runs are seen as changes. A requested thin build
+// first compares them to their current state, any difference makes the builder do a fat rebuild
+// instead. Source edits in the top-level crate and in workspace libraries leave all of them untouched.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    context::Context,
+    workspace::{self, WorkspacePackage},
+};
+
+/// Envs cargo sets for every rustc invocation, they only change along with a tracked file
+const CARGO_ENV_PREFIXES: &[&str] = &["CARGO", "DX_", "RUSTC", "SUBSECOND_", "__CARGO"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum InputKind {
+    Manifest,
+    Lockfile,
+    Config,
+    BuildScript(String),
+    ProcMacro(String),
+}
+
+/// Why a thin build can't be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FatReason {
+    Manifest(PathBuf),
+    Lockfile,
+    Config(PathBuf),
+    BuildScript { package: String, input: PathBuf },
+    ProcMacro(String),
+    Env(String),
+}
+
+impl fmt::Display for FatReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FatReason::Manifest(path) => write!(f, "{} changed", path.display()),
+            FatReason::Lockfile => write!(f, "Cargo.lock changed"),
+            FatReason::Config(path) => write!(f, "{} changed", path.display()),
+            FatReason::BuildScript { package, input } => {
+                write!(
+                    f,
+                    "input {} of the build script of {package} changed",
+                    input.display()
+                )
+            }
+            FatReason::ProcMacro(package) => write!(f, "proc-macro crate {package} changed"),
+            FatReason::Env(key) => write!(f, "env var {key} changed"),
+        }
+    }
+}
+
+/// State of the inputs at the start of the last fat build
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Hash of every tracked file or dir, `None` if it didn't exist
+    inputs: Vec<(InputKind, PathBuf, Option<u64>)>,
+    envs: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    /// Records the inputs, called before cargo is spawned for a fat build
+    pub fn record(ctx: &Context) -> Self {
+        let (root, packages) = workspace::workspace_packages(ctx);
+        let mut inputs = tracked_inputs(ctx, &root, &packages);
+        for (_, path, hash) in &mut inputs {
+            *hash = hash_path(path);
+        }
+        Self {
+            inputs,
+            envs: build_envs(ctx, &root),
+        }
+    }
+
+    /// Adds the inputs build scripts asked for in the fat build that weren't known before it, e.g.
+    /// on the first build of a package
+    pub fn add_build_script_inputs(&mut self, ctx: &Context) {
+        let (root, packages) = workspace::workspace_packages(ctx);
+        for (kind, path, _) in tracked_inputs(ctx, &root, &packages) {
+            if !self.inputs.iter().any(|(_, known, _)| *known == path) {
+                let hash = hash_path(&path);
+                self.inputs.push((kind, path, hash));
+            }
+        }
+    }
+
+    /// The first change that needs a fat build, `None` if a thin build is enough
+    pub fn fat_reason(&self, ctx: &Context) -> Option<FatReason> {
+        for (kind, path, hash) in &self.inputs {
+            if hash_path(path) == *hash {
+                continue;
+            }
+            return Some(match kind {
+                InputKind::Manifest => FatReason::Manifest(path.clone()),
+                InputKind::Lockfile => FatReason::Lockfile,
+                InputKind::Config => FatReason::Config(path.clone()),
+                InputKind::BuildScript(package) => FatReason::BuildScript {
+                    package: package.clone(),
+                    input: path.clone(),
+                },
+                InputKind::ProcMacro(package) => FatReason::ProcMacro(package.clone()),
+            });
+        }
+
+        let (root, _) = workspace::workspace_packages(ctx);
+        let envs = build_envs(ctx, &root);
+        self.envs
+            .keys()
+            .chain(envs.keys())
+            .find(|key| self.envs.get(*key) != envs.get(*key))
+            .map(|key| FatReason::Env(key.clone()))
+    }
+}
+
+/// Environment the rustc invocations of the next build get, apart from the envs cargo sets itself
+fn build_envs(ctx: &Context, root: &Path) -> BTreeMap<String, String> {
+    let mut envs = std::env::vars().collect::<BTreeMap<_, _>>();
+    envs.extend(ctx.build_envs());
+
+    // `[env]` of the cargo config, closer configs win and only `force` overrides the environment
+    let mut config_envs = BTreeMap::new();
+    for config in cargo_configs(ctx, root).iter().rev() {
+        let Some(table) = std::fs::read_to_string(config)
+            .ok()
+            .and_then(|contents| contents.parse::<toml::Table>().ok())
+        else {
+            continue;
+        };
+        let Some(toml::Value::Table(env)) = table.get("env") else {
+            continue;
+        };
+        // `<dir>/.cargo/config.toml`, relative values are relative to `<dir>`
+        let config_root = config.parent().and_then(Path::parent).unwrap();
+        for (key, value) in env {
+            let entry = match value {
+                toml::Value::String(value) => (value.clone(), false),
+                toml::Value::Table(table) => {
+                    let Some(value) = table.get("value").and_then(|v| v.as_str()) else {
+                        continue;
+                    };
+                    let flag = |name| table.get(name).and_then(|v| v.as_bool()) == Some(true);
+                    let value = if flag("relative") {
+                        config_root.join(value).display().to_string()
+                    } else {
+                        value.to_string()
+                    };
+                    (value, flag("force"))
+                }
+                _ => continue,
+            };
+            config_envs.insert(key.clone(), entry);
+        }
+    }
+    for (key, (value, force)) in config_envs {
+        if force || !envs.contains_key(&key) {
+            envs.insert(key, value);
+        }
+    }
+
+    envs.retain(|key, _| !CARGO_ENV_PREFIXES.iter().any(|p| key.starts_with(p)));
+    envs
+}
+
+/// Cargo config files that apply to the project, the closest first
+fn cargo_configs(ctx: &Context, root: &Path) -> Vec<PathBuf> {
+    let mut dirs = ctx
+        .working_dir
+        .ancestors()
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    if !dirs.iter().any(|dir| dir == root) {
+        dirs.push(root.to_path_buf());
+    }
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")));
+
+    dirs.iter()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home)
+        .flat_map(|dir| [dir.join("config.toml"), dir.join("config")])
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn tracked_inputs(
+    ctx: &Context,
+    root: &Path,
+    packages: &[WorkspacePackage],
+) -> Vec<(InputKind, PathBuf, Option<u64>)> {
+    let mut inputs = vec![
+        (InputKind::Manifest, root.join("Cargo.toml")),
+        (InputKind::Manifest, ctx.working_dir.join("Cargo.toml")),
+        (InputKind::Lockfile, root.join("Cargo.lock")),
+        (InputKind::Config, root.join("rust-toolchain")),
+        (InputKind::Config, root.join("rust-toolchain.toml")),
+    ];
+    for dir in [root, ctx.working_dir.as_path()] {
+        inputs.push((InputKind::Config, dir.join(".cargo").join("config.toml")));
+        inputs.push((InputKind::Config, dir.join(".cargo").join("config")));
+    }
+    for config in cargo_configs(ctx, root) {
+        inputs.push((InputKind::Config, config));
+    }
+
+    for package in packages {
+        inputs.push((InputKind::Manifest, package.manifest_path.clone()));
+        let package_dir = package.manifest_path.parent().unwrap();
+        if let Some(build_script) = &package.build_script {
+            let kind = InputKind::BuildScript(package.name.clone());
+            inputs.push((kind.clone(), build_script.clone()));
+            for path in build_script_output(ctx, package, "rerun-if-changed") {
+                inputs.push((kind.clone(), package_dir.join(path)));
+            }
+        }
+        if let Some(proc_macro) = &package.proc_macro {
+            // the whole source dir, macros can be split over several modules
+            let src_dir = proc_macro.parent().unwrap().to_path_buf();
+            inputs.push((InputKind::ProcMacro(package.name.clone()), src_dir));
+        }
+    }
+
+    inputs.sort_by(|a, b| a.1.cmp(&b.1));
+    inputs.dedup_by(|a, b| a.1 == b.1);
+    inputs
+        .into_iter()
+        .map(|(kind, path)| (kind, path, None))
+        .collect()
+}
+
+/// Values of a `cargo:<key>=` instruction printed by the last run of the build script of `package`
+fn build_script_output(ctx: &Context, package: &WorkspacePackage, key: &str) -> Vec<String> {
+    let build_dir = ctx.target_triple_profile_dir().join("build");
+    let Ok(entries) = std::fs::read_dir(&build_dir) else {
+        return vec![];
+    };
+    let old_syntax = format!("cargo:{key}=");
+    let new_syntax = format!("cargo::{key}=");
+    entries
+        .flatten()
+        .filter(|entry| {
+            // `<package>-<hash>`
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.rsplit_once('-')
+                .is_some_and(|(name, _)| name == package.name)
+        })
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("output")).ok())
+        .flat_map(|output| {
+            output
+                .lines()
+                .filter_map(|line| {
+                    line.strip_prefix(&new_syntax)
+                        .or_else(|| line.strip_prefix(&old_syntax))
+                })
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Hash of the contents of a file, or of every file below a dir
+fn hash_path(path: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    if path.is_dir() {
+        let mut files = vec![];
+        collect_files(path, &mut files);
+        files.sort();
+        for file in files {
+            file.hash(&mut hasher);
+            std::fs::read(&file).ok()?.hash(&mut hasher);
+        }
+    } else {
+        std::fs::read(path).ok()?.hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Hot-patch client injected into every HTML page by the proxy.
+//
+// Applying a jump table requires access to the wasm module internals, so the actual patching is
+// delegated to the app: register `window.__subsecond_apply_patch = (jumpTable) => ...` (usually done
+// by the devtools integration inside the wasm app). Without it patches are only logged.
+//
+// The app should also set `window.__subsecond_versions = { devtools: "...", subsecond: "..." }` with
+// the versions it links, they are announced to the server which refuses to patch incompatible apps.
+//
+// Patches calling wasm-bindgen imports the fat build didn't have come with a JS shim (a `.js` entry
+// in the message's `assets`). It is imported before the patch is applied and its functions are added
+// to the imports of the patch module when the app instantiates it. The shim shares the JS heap of the
+// app through the helpers its glue publishes as `__subsecond_glue`.
+(() => {
+  if (window.__subsecond_client) {
+    return;
+  }
+  window.__subsecond_client = true;
+
+  const HOTPATCH_ADDR = "{{HOTPATCH_ADDR}}";
+  const GENERATION = {{GENERATION}};
+  // build of the wasm module this page loaded, patches for other builds would corrupt it
+  const BUILD_ID = {{BUILD_ID}};
+
+  const SHIM_MODULE = "__subsecond_wbg_shim__";
+
+  let overlay = null;
+  // shim of the patch being applied
+  let currentShim = null;
+
+  function withShim(imports) {
+    if (currentShim && imports && imports.env && !imports[SHIM_MODULE]) {
+      return { ...imports, [SHIM_MODULE]: currentShim.__subsecond_shim(imports.env, window.__subsecond_glue || {}) };
+    }
+    return imports;
+  }
+
+  const instantiate = WebAssembly.instantiate;
+  const instantiateStreaming = WebAssembly.instantiateStreaming;
+  WebAssembly.instantiate = (source, imports) => instantiate(source, withShim(imports));
+  if (instantiateStreaming) {
+    WebAssembly.instantiateStreaming = (source, imports) =>
+      instantiateStreaming(source, withShim(imports));
+  }
+
+  function showOverlay(text, color) {
+    if (!overlay) {
+      overlay = document.createElement("div");
+      overlay.style.cssText =
+        "position:fixed;bottom:12px;right:12px;z-index:2147483647;padding:8px 12px;" +
+        "border-radius:6px;font:13px monospace;color:#fff;box-shadow:0 2px 8px rgba(0,0,0,.3)";
+      document.body.appendChild(overlay);
+    }
+    overlay.style.background = color;
+    overlay.textContent = text;
+  }
+
+  function hideOverlay(delay) {
+    setTimeout(() => {
+      if (overlay) {
+        overlay.remove();
+        overlay = null;
+      }
+    }, delay);
+  }
+
+  function handleMessage(msg) {
+    if (msg === "HotPatchStart") {
+      showOverlay("Hot-patching...", "#444");
+    } else if (msg === "FullReloadStart") {
+      showOverlay("Rebuilding...", "#444");
+    } else if (msg === "FullReloadFailed") {
+      showOverlay("Rebuild failed, see terminal", "#b00020");
+    } else if (msg === "FullReloadCommand") {
+      window.location.reload();
+    } else if (msg.HotReload && msg.HotReload.jump_table) {
+      const forBuild = msg.HotReload.for_build_id;
+      if (BUILD_ID !== 0 && forBuild != null && forBuild !== BUILD_ID) {
+        console.warn(`[subsecond] ignoring patch for build ${forBuild}, this page runs build ${BUILD_ID}`);
+        showOverlay("Page is outdated, reload it", "#b00020");
+        return;
+      }
+      if (typeof window.__subsecond_apply_patch !== "function") {
+        console.warn("[subsecond] received a patch but no __subsecond_apply_patch handler is registered");
+        return;
+      }
+      const shimUrl = (msg.HotReload.assets || []).find((asset) => asset.endsWith(".js"));
+      const shim = shimUrl ? import(shimUrl) : Promise.resolve(null);
+      shim
+        .then((shim) => {
+          currentShim = shim;
+          window.__subsecond_apply_patch(msg.HotReload.jump_table);
+          showOverlay("Patched", "#2e7d32");
+          hideOverlay(1000);
+        })
+        .catch((err) => {
+          console.error("[subsecond] failed to load the wasm-bindgen shim of the patch", err);
+          showOverlay("Hot-patch failed, see console", "#b00020");
+        });
+    }
+  }
+
+  function connect() {
+    const versions = window.__subsecond_versions || {};
+    const params = new URLSearchParams();
+    if (BUILD_ID !== 0) {
+      params.set("build_id", BUILD_ID);
+    }
+    if (versions.devtools) {
+      params.set("devtools_version", versions.devtools);
+    }
+    if (versions.subsecond) {
+      params.set("subsecond_version", versions.subsecond);
+    }
+    const ws = new WebSocket(`ws://${HOTPATCH_ADDR}/?${params}`);
+    ws.onmessage = (event) => handleMessage(JSON.parse(event.data));
+    ws.onclose = (event) => {
+      // policy violation: the server refused the client because of a version mismatch
+      if (event.code === 1008) {
+        showOverlay(`Hot-patching disabled: ${event.reason}`, "#b00020");
+        return;
+      }
+      setTimeout(connect, 1000);
+    };
+  }
+
+  // The proxy bumps the generation after every fat build, at which point the page is stale
+  function pollStatus() {
+    fetch("/__subsecond/status")
+      .then((res) => res.json())
+      .then((status) => {
+        if (status.rebuilding) {
+          showOverlay("Rebuilding...", "#444");
+        } else if (status.generation !== GENERATION) {
+          window.location.reload();
+        }
+      })
+      .catch(() => {})
+      .finally(() => setTimeout(pollStatus, 1000));
+  }
+
+  connect();
+  pollStatus();
+})();
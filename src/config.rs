@@ -0,0 +1,268 @@
+// Project configuration read from the package metadata in Cargo.toml:
+//
+// ```toml
+// [package.metadata.leptos]
+// site-root = "target/site"
+// site-pkg-dir = "pkg"
+// site-addr = "127.0.0.1:3000"
+// reload-port = 3002          # 3001 is taken by the proxy (`--proxy-addr`)
+// assets-dir = "public"
+// hash-files = true
+//
+// [package.metadata.leptos-subsecond]
+// base-path = "/app"        # path the site is mounted at
+//
+// [package.metadata.leptos-subsecond.run]
+// args = ["--verbose"]
+// env = { RUST_LOG = "info" }
+// env-file = ".env"
+// cwd = "."
+//
+// [package.metadata.leptos-subsecond.bindgen]
+// target = "web"            # web, bundler, no-modules or deno
+// typescript = false
+// debug = true
+// keep-debug = true
+// out-name = "app"          # defaults to the binary name
+// out-dir = "frontend/wasm" # defaults to the site pkg dir
+// public-path = "/wasm/"    # URL out-dir is served at, defaults to base-path + site-pkg-dir
+// ```
+//
+// Command line options take precedence over the values found here.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::Deserialize;
+
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LeptosMetadata {
+    pub site_root: Option<String>,
+    pub site_pkg_dir: Option<String>,
+    pub site_addr: Option<String>,
+    pub reload_port: Option<u16>,
+    /// Static files copied into the site root by release builds
+    pub assets_dir: Option<String>,
+    /// Content-hashed names for the client files, also in dev builds
+    pub hash_files: bool,
+}
+
+impl LeptosMetadata {
+    pub fn site_root(&self) -> String {
+        self.site_root
+            .clone()
+            .unwrap_or_else(|| "target/site".to_string())
+    }
+
+    pub fn site_pkg_dir(&self) -> String {
+        self.site_pkg_dir
+            .clone()
+            .unwrap_or_else(|| "pkg".to_string())
+    }
+}
+
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ToolMetadata {
+    pub base_path: Option<String>,
+    pub run: RunConfig,
+    pub bindgen: BindgenConfig,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BindgenTarget {
+    #[default]
+    Web,
+    Bundler,
+    NoModules,
+    Deno,
+}
+
+/// wasm-bindgen options of the client build
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct BindgenConfig {
+    pub target: BindgenTarget,
+    pub typescript: bool,
+    pub debug: bool,
+    /// Hot-patching needs the names kept by debug info, only turn it off for inspection
+    pub keep_debug: bool,
+    pub out_name: Option<String>,
+    /// Where the generated files and patches are written, relative to the project dir
+    pub out_dir: Option<PathBuf>,
+    /// URL prefix `out_dir` is served at, patches are loaded from there
+    pub public_path: Option<String>,
+}
+
+impl Default for BindgenConfig {
+    fn default() -> Self {
+        Self {
+            target: BindgenTarget::default(),
+            typescript: false,
+            debug: true,
+            keep_debug: true,
+            out_name: None,
+            out_dir: None,
+            public_path: None,
+        }
+    }
+}
+
+/// How the binary produced by a fat build is launched
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RunConfig {
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub env_file: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    /// Runtime launching WASI modules, `wasmtime` from `PATH` by default
+    pub wasi_runtime: Option<PathBuf>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct ProjectMetadata {
+    pub leptos: LeptosMetadata,
+    pub tool: ToolMetadata,
+}
+
+impl ProjectMetadata {
+    /// Settles the URL prefix wasm patches are loaded from: `public_url` given on the command line,
+    /// then `bindgen.public-path`, then `base-path` followed by the site pkg dir
+    pub fn resolve_public_path(&mut self, public_url: Option<&str>) {
+        let public_path = match (public_url, &self.tool.bindgen.public_path) {
+            (Some(public_url), _) => public_url.to_string(),
+            (None, Some(public_path)) => public_path.clone(),
+            (None, None) => {
+                let base_path = self.tool.base_path.as_deref().unwrap_or("/");
+                format!(
+                    "{}/{}",
+                    base_path.trim_end_matches('/'),
+                    self.leptos.site_pkg_dir().trim_matches('/')
+                )
+            }
+        };
+        // patch file names are appended to it
+        let public_path = if public_path.ends_with('/') {
+            public_path
+        } else {
+            format!("{public_path}/")
+        };
+        self.tool.bindgen.public_path = Some(public_path);
+    }
+}
+
+impl RunConfig {
+    /// Applies options given on the command line on top of this config
+    pub fn merge_cli(
+        &mut self,
+        args: &[String],
+        envs: &[String],
+        env_file: Option<&PathBuf>,
+        cwd: Option<&PathBuf>,
+    ) {
+        self.args.extend(args.iter().cloned());
+        for env in envs {
+            match env.split_once('=') {
+                Some((key, value)) => {
+                    self.env.insert(key.to_string(), value.to_string());
+                }
+                None => tracing::warn!("Ignoring env `{env}`, expected KEY=VALUE"),
+            }
+        }
+        if let Some(env_file) = env_file {
+            self.env_file = Some(env_file.clone());
+        }
+        if let Some(cwd) = cwd {
+            self.cwd = Some(cwd.clone());
+        }
+    }
+}
+
+/// Reads `package.metadata` of `package` using `cargo metadata`, missing metadata results in defaults
+pub fn load_project_metadata(manifest_path: &Path, package: &str) -> ProjectMetadata {
+    let output = Command::new("cargo")
+        .arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .output();
+
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            tracing::warn!(
+                "cargo metadata failed, using default config: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return ProjectMetadata::default();
+        }
+        Err(err) => {
+            tracing::warn!("Couldn't run cargo metadata, using default config: {err}");
+            return ProjectMetadata::default();
+        }
+    };
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let Some(package_metadata) = metadata["packages"]
+        .as_array()
+        .and_then(|packages| packages.iter().find(|p| p["name"] == package))
+        .map(|p| &p["metadata"])
+    else {
+        tracing::warn!("Package {package} not found in cargo metadata");
+        return ProjectMetadata::default();
+    };
+
+    ProjectMetadata {
+        leptos: parse_section(package_metadata, "leptos"),
+        tool: parse_section(package_metadata, "leptos-subsecond"),
+    }
+}
+
+fn parse_section<T: for<'de> Deserialize<'de> + Default>(
+    metadata: &serde_json::Value,
+    section: &str,
+) -> T {
+    match metadata.get(section) {
+        Some(value) => serde_json::from_value(value.clone()).unwrap_or_else(|err| {
+            tracing::error!("Invalid [package.metadata.{section}]: {err}");
+            T::default()
+        }),
+        None => T::default(),
+    }
+}
+
+/// Parses a dotenv file, supports comments, `export` prefixes and quoted values
+pub fn read_env_file(path: &Path) -> Vec<(String, String)> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            tracing::error!("Couldn't read env file {}: {err}", path.display());
+            return vec![];
+        }
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
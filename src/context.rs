@@ -1,13 +1,19 @@
 use std::{
     path::{Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use target_lexicon::{OperatingSystem, Triple};
 use tempfile::NamedTempFile;
 use wasm_bindgen_cli_support::Bindgen;
 
-use crate::{LinkerFlavor, patch::prepare_wasm_base_module};
+use crate::{
+    LinkerFlavor,
+    config::{BindgenConfig, BindgenTarget, RunConfig, read_env_file},
+    hash_files::HashedFiles,
+    patch::prepare_wasm_base_module,
+    wbg_shim,
+};
 
 pub struct Context {
     pub working_dir: PathBuf,
@@ -28,6 +34,21 @@ pub struct Context {
     pub site_dir: String,
     pub site_pkg_dir: String,
     pub wasm_bindgen_dir: String,
+    /// Address the launched binary listens on, used to wait for the port to be freed before relaunching
+    pub site_addr: Option<String>,
+    pub reload_port: Option<u16>,
+    /// Write the client files of fat builds with content-hashed names, see `hash_files`
+    pub hash_files: bool,
+    pub run: RunConfig,
+    pub auto_restart: bool,
+    pub stop_grace_period: Duration,
+    /// See [`crate::gc::prune_fat_archives`]
+    pub keep_fat_archives: usize,
+    /// Keep thin link inputs for debugging, see [`crate::report`]
+    pub keep_temps: bool,
+    /// Start a fat rebuild when a patch fails with [`crate::patch::PatchError::UnresolvedImports`]
+    pub fat_rebuild_on_unresolved: bool,
+    pub bindgen: BindgenConfig,
 }
 
 impl Context {
@@ -43,6 +64,88 @@ impl Context {
         self.site_dir_path().join(&self.site_pkg_dir)
     }
 
+    /// Where wasm-bindgen output and wasm patches are written
+    pub fn pkg_out_path(&self) -> PathBuf {
+        match &self.bindgen.out_dir {
+            Some(out_dir) => self.working_dir.join(out_dir),
+            None => self.site_pkg_path(),
+        }
+    }
+
+    /// URL a file written to [`Self::pkg_out_path`] is loaded from by the browser, see
+    /// [`crate::config::ProjectMetadata::resolve_public_path`]
+    pub fn pkg_url(&self, file_name: impl AsRef<Path>) -> PathBuf {
+        let public_path = self.bindgen.public_path.as_deref().unwrap_or("/pkg/");
+        PathBuf::from(public_path).join(file_name)
+    }
+
+    pub fn bindgen_out_name(&self) -> String {
+        self.bindgen
+            .out_name
+            .clone()
+            .unwrap_or_else(|| self.final_binary_name())
+    }
+
+    /// Name the server looks the client files up by, the client is named after the package
+    fn leptos_output_name(&self) -> String {
+        self.bindgen
+            .out_name
+            .clone()
+            .unwrap_or_else(|| self.package.clone())
+    }
+
+    /// Site pkg dir the server serves the client files from, `bindgen.out-dir` if it's inside the site
+    fn leptos_site_pkg_dir(&self) -> String {
+        self.bindgen
+            .out_dir
+            .as_ref()
+            .and_then(|out_dir| out_dir.strip_prefix(&self.site_dir).ok())
+            .map(|pkg_dir| pkg_dir.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.site_pkg_dir.clone())
+    }
+
+    /// Working directory of the launched binary, relative paths are resolved from the project dir
+    pub fn run_cwd(&self) -> PathBuf {
+        match &self.run.cwd {
+            Some(cwd) => self.working_dir.join(cwd),
+            None => self.working_dir.clone(),
+        }
+    }
+
+    /// Environment of the launched binary: leptos defaults, then the env file, then explicit envs
+    pub fn run_envs(&self) -> Vec<(String, String)> {
+        let mut envs = vec![
+            ("LEPTOS_OUTPUT_NAME".to_string(), self.leptos_output_name()),
+            ("LEPTOS_SITE_ROOT".to_string(), self.site_dir.clone()),
+            (
+                "LEPTOS_SITE_PKG_DIR".to_string(),
+                self.leptos_site_pkg_dir(),
+            ),
+        ];
+        if let Some(site_addr) = &self.site_addr {
+            envs.push(("LEPTOS_SITE_ADDR".to_string(), site_addr.clone()));
+        }
+        if let Some(reload_port) = self.reload_port {
+            envs.push(("LEPTOS_RELOAD_PORT".to_string(), reload_port.to_string()));
+        }
+        if self.hash_files {
+            envs.push(("LEPTOS_HASH_FILES".to_string(), "true".to_string()));
+        }
+        envs.extend(self.build_envs());
+        envs
+    }
+
+    /// Envs of the launched binary that are also set for cargo, so `env!` sees the env file and
+    /// explicit envs the binary runs with
+    pub fn build_envs(&self) -> Vec<(String, String)> {
+        let mut envs = vec![];
+        if let Some(env_file) = &self.run.env_file {
+            envs.extend(read_env_file(&self.working_dir.join(env_file)));
+        }
+        envs.extend(self.run.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        envs
+    }
+
     pub fn target_triple_profile_dir(&self) -> PathBuf {
         self.target_dir
             .join(self.triple.to_string())
@@ -53,6 +156,13 @@ impl Context {
         self.target_dir.join(&self.wasm_bindgen_dir)
     }
 
+    /// JS glue wasm-bindgen generated for the fat build
+    pub fn bindgen_glue_path(&self) -> PathBuf {
+        self.wasm_bindgen_dir_path()
+            .join(self.bindgen_out_name())
+            .with_extension("js")
+    }
+
     pub fn linker_flavor(&self) -> LinkerFlavor {
         match self.triple.environment {
             target_lexicon::Environment::Gnu
@@ -84,7 +194,20 @@ impl Context {
         matches!(
             self.triple.architecture,
             target_lexicon::Architecture::Wasm32 | target_lexicon::Architecture::Wasm64
-        ) || self.triple.operating_system == target_lexicon::OperatingSystem::Wasi
+        ) || self.is_wasi()
+    }
+
+    /// WASI modules run under a local runtime instead of a browser, see [`crate::wasi`]
+    pub fn is_wasi(&self) -> bool {
+        matches!(
+            self.triple.operating_system,
+            OperatingSystem::Wasi | OperatingSystem::WasiP1 | OperatingSystem::WasiP2
+        )
+    }
+
+    /// Wasm running in the browser, patches are served from the site pkg dir
+    pub fn is_web(&self) -> bool {
+        self.is_wasm_or_wasi() && !self.is_wasi()
     }
 
     pub fn final_binary_name(&self) -> String {
@@ -121,20 +244,39 @@ impl Context {
         path.with_extension(extension)
     }
 
-    pub fn write_executable(&self, compiled: &Path) -> PathBuf {
-        if self.is_wasm_or_wasi() {
-            self.write_with_bindgen(compiled)
+    pub fn write_executable(&self, compiled: &Path) -> anyhow::Result<PathBuf> {
+        if self.is_wasi() {
+            self.write_wasi(compiled)
+        } else if self.is_wasm_or_wasi() {
+            Ok(self.write_with_bindgen(compiled))
         } else {
-            self.write_native(compiled)
+            Ok(self.write_native(compiled))
         }
     }
 
     pub fn write_native(&self, binary: &Path) -> PathBuf {
-        let bundle_exe = self.bundle_path.join(&self.final_binary_name());
-        std::fs::copy(&binary, &bundle_exe).unwrap();
+        let bundle_exe = self.bundle_path.join(self.final_binary_name());
+        std::fs::copy(binary, &bundle_exe).unwrap();
         bundle_exe
     }
 
+    /// WASI modules don't go through wasm-bindgen and are never patched, see [`crate::wasi`]
+    pub fn write_wasi(&self, wasm: &Path) -> anyhow::Result<PathBuf> {
+        if self.triple.operating_system == OperatingSystem::WasiP2 {
+            anyhow::bail!(
+                "{} builds a wasm component, which isn't supported, use wasm32-wasip1",
+                self.triple
+            );
+        }
+        std::fs::create_dir_all(&self.bundle_path).unwrap();
+        let bundle_wasm = self
+            .bundle_path
+            .join(self.final_binary_name())
+            .with_extension("wasm");
+        std::fs::copy(wasm, &bundle_wasm).unwrap();
+        Ok(bundle_wasm)
+    }
+
     pub fn write_with_bindgen(&self, wasm: &Path) -> PathBuf {
         let wasm_bindgen_dir = self.wasm_bindgen_dir_path();
         let _ = std::fs::remove_dir_all(&wasm_bindgen_dir);
@@ -147,23 +289,36 @@ impl Context {
         tracing::info!("Preparing wasm file finished");
 
         tracing::info!("Running wasm-bindgen");
-        let mut bindgen = Bindgen::new()
+        let mut bindgen = Bindgen::new();
+        bindgen
             .keep_lld_exports(true)
             .demangle(false) // do not demangle names, hotpatchmodulecache ifunc map not populated properly with demangled names for some reason
-            .debug(true)
-            .keep_debug(true)
+            .debug(self.bindgen.debug)
+            .keep_debug(self.bindgen.keep_debug)
+            .typescript(self.bindgen.typescript)
             .input_path(wasm)
-            .out_name(&self.final_binary_name())
-            .web(true)
-            .unwrap()
-            .generate_output()
-            .unwrap();
+            .out_name(&self.bindgen_out_name());
+        match self.bindgen.target {
+            BindgenTarget::Web => bindgen.web(true),
+            BindgenTarget::Bundler => bindgen.bundler(true),
+            BindgenTarget::NoModules => bindgen.no_modules(true),
+            BindgenTarget::Deno => bindgen.deno(true),
+        }
+        .unwrap();
+        let mut output = bindgen.generate_output().unwrap();
 
-        bindgen.emit(&wasm_bindgen_dir).unwrap();
+        output.emit(&wasm_bindgen_dir).unwrap();
+        if self.bindgen.target == BindgenTarget::Web {
+            // shims of later patches share the heap of this glue
+            let glue_path = self.bindgen_glue_path();
+            let mut glue = std::fs::read_to_string(&glue_path).unwrap();
+            glue.push_str(&wbg_shim::expose_helpers(&glue));
+            std::fs::write(&glue_path, glue).unwrap();
+        }
         tracing::info!("Finished wasm-bindgen");
 
         let wasm_path = wasm_bindgen_dir
-            .join(format!("{}_bg", self.final_binary_name()))
+            .join(format!("{}_bg", self.bindgen_out_name()))
             .with_extension("wasm");
 
         self.write_fat_wasm_to_pkg();
@@ -172,33 +327,77 @@ impl Context {
     }
 
     pub fn write_fat_wasm_to_pkg(&self) {
-        std::fs::create_dir_all(self.site_pkg_path()).unwrap();
+        let pkg_path = self.pkg_out_path();
+        std::fs::create_dir_all(&pkg_path).unwrap();
 
         let wasm_bindgen_dir = self.wasm_bindgen_dir_path();
+        let out_name = self.bindgen_out_name();
+
+        // the glue of the other targets imports `<name>_bg.wasm` (and `<name>_bg.js`) by name, so the
+        // output is copied as is
+        if self.bindgen.target != BindgenTarget::Web {
+            copy_dir_all(&wasm_bindgen_dir, &pkg_path).unwrap();
+            return;
+        }
 
         let wb_wasm_path = wasm_bindgen_dir
-            .join(format!("{}_bg", self.final_binary_name()))
-            .with_extension("wasm");
-        let pkg_wasm_path = self
-            .site_pkg_path()
-            .join(self.final_binary_name())
+            .join(format!("{out_name}_bg"))
             .with_extension("wasm");
+        let wb_js_path = self.bindgen_glue_path();
 
-        std::fs::copy(&wb_wasm_path, pkg_wasm_path).unwrap();
-
-        let wb_js_path = wasm_bindgen_dir
-            .join(self.final_binary_name())
-            .with_extension("js");
-        let pkg_js_path = self
-            .site_pkg_path()
-            .join(self.final_binary_name())
-            .with_extension("js");
+        if self.hash_files {
+            let mut hashed = HashedFiles::default();
+            hashed
+                .write(
+                    &pkg_path,
+                    &out_name,
+                    "js",
+                    &std::fs::read(&wb_js_path).unwrap(),
+                )
+                .unwrap();
+            hashed
+                .write(
+                    &pkg_path,
+                    &out_name,
+                    "wasm",
+                    &std::fs::read(&wb_wasm_path).unwrap(),
+                )
+                .unwrap();
+            hashed.remove_stale(&pkg_path, &out_name);
+            hashed.write_manifest(&pkg_path).unwrap();
+            // the server binary is launched from the bundle dir
+            std::fs::create_dir_all(&self.bundle_path).unwrap();
+            hashed.write_hash_txt(&self.bundle_path).unwrap();
+        } else {
+            let pkg_wasm_path = pkg_path.join(&out_name).with_extension("wasm");
+            std::fs::copy(&wb_wasm_path, pkg_wasm_path).unwrap();
+            let pkg_js_path = pkg_path.join(&out_name).with_extension("js");
+            std::fs::copy(&wb_js_path, pkg_js_path).unwrap();
+        }
 
-        std::fs::copy(&wb_js_path, pkg_js_path).unwrap();
+        if self.bindgen.typescript {
+            let name = format!("{out_name}.d.ts");
+            std::fs::copy(wasm_bindgen_dir.join(&name), pkg_path.join(&name)).unwrap();
+        }
     }
 
     pub fn write_thin_wasm_patch_to_pkg(&self, patch_path: &Path) {
         let patch_name = patch_path.file_name().unwrap();
-        std::fs::copy(patch_path, self.site_pkg_path().join(patch_name)).unwrap();
+        std::fs::copy(patch_path, self.pkg_out_path().join(patch_name)).unwrap();
+    }
+}
+
+/// Copies `from` into `to` recursively, wasm-bindgen puts inline JS into a `snippets` dir
+pub fn copy_dir_all(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,340 @@
+// `doctor` subcommand: checks the toolchain and the project before building.
+//
+// Missing tools and mismatched versions otherwise only show up as panics deep inside a fat build or
+// wasm-bindgen, so every check here comes with the command that fixes it.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::protocol::{DEVTOOLS_VERSION, is_compatible};
+
+/// Version of `wasm-bindgen-cli-support` this tool is built with, the project must use the same
+/// `wasm-bindgen` version since the schema of the custom sections is checked by the cli.
+const BUNDLED_WASM_BINDGEN: &str = "0.2.100";
+const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+struct Check {
+    name: &'static str,
+    severity: Severity,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Runs every check and prints the results, returns `false` if any check failed
+pub fn run(manifest_path: &Path, package: Option<&str>) -> bool {
+    let mut checks = vec![
+        tool_check("cargo", "cargo", "Install Rust from https://rustup.rs"),
+        dx_check(),
+        tool_check(
+            "cc",
+            "cc",
+            "Install a C toolchain, e.g. `sudo apt install build-essential` or `xcode-select --install`",
+        ),
+        tool_check(
+            "wasm-ld",
+            "wasm-ld",
+            "Install lld, e.g. `sudo apt install lld` or `brew install llvm` and add it to PATH",
+        ),
+        wasm_target_check(),
+    ];
+
+    match cargo_metadata(manifest_path) {
+        Ok(metadata) => checks.extend(project_checks(&metadata, package)),
+        Err(err) => checks.push(Check::error(
+            "cargo metadata",
+            err,
+            format!(
+                "Make sure `cargo metadata --manifest-path {}` works",
+                manifest_path.display()
+            ),
+        )),
+    }
+
+    let mut healthy = true;
+    for check in &checks {
+        let label = match check.severity {
+            Severity::Ok => "\x1b[32m  ok \x1b[0m",
+            Severity::Warning => "\x1b[33mwarn \x1b[0m",
+            Severity::Error => {
+                healthy = false;
+                "\x1b[31merror\x1b[0m"
+            }
+        };
+        println!("[{label}] {}: {}", check.name, check.message);
+        if let Some(fix) = &check.fix {
+            println!("        fix: {fix}");
+        }
+    }
+
+    if healthy {
+        println!("\nEverything looks good");
+    } else {
+        println!("\nSome checks failed, builds are likely to fail until they are fixed");
+    }
+    healthy
+}
+
+/// Runs `<program> --version`
+fn tool_check(name: &'static str, program: &str, fix: impl Into<String>) -> Check {
+    match Command::new(program).arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            Check::ok(name, version.lines().next().unwrap_or_default().trim())
+        }
+        Ok(output) => Check::error(
+            name,
+            format!(
+                "`{program} --version` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            fix,
+        ),
+        Err(_) => Check::error(name, format!("`{program}` not found in PATH"), fix),
+    }
+}
+
+/// `dx` links the fat builds and its linker args have to match the devtools protocol
+fn dx_check() -> Check {
+    let fix = format!("cargo install dioxus-cli --version {DEVTOOLS_VERSION}");
+    let check = tool_check("dx", "dx", fix.clone());
+    let Severity::Ok = check.severity else {
+        return check;
+    };
+    // e.g. `dioxus 0.7.0-alpha.3 (1a2b3c4)`
+    let version = check
+        .message
+        .split_whitespace()
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()));
+    match version {
+        Some(version) if is_compatible(version, DEVTOOLS_VERSION) => check,
+        Some(version) => Check::warning(
+            "dx",
+            format!(
+                "dx {version} doesn't match dioxus-devtools {DEVTOOLS_VERSION} used by this tool"
+            ),
+            fix,
+        ),
+        None => Check::warning(
+            "dx",
+            format!("Couldn't find a version in `{}`", check.message),
+            fix,
+        ),
+    }
+}
+
+fn wasm_target_check() -> Check {
+    let fix = format!("rustup target add {WASM_TARGET}");
+    let output = Command::new("rustup")
+        .arg("target")
+        .arg("list")
+        .arg("--installed")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let installed = String::from_utf8_lossy(&output.stdout);
+            if installed.lines().any(|line| line.trim() == WASM_TARGET) {
+                Check::ok("wasm32 target", format!("{WASM_TARGET} is installed"))
+            } else {
+                Check::error(
+                    "wasm32 target",
+                    format!("{WASM_TARGET} is not installed"),
+                    fix,
+                )
+            }
+        }
+        _ => Check::warning(
+            "wasm32 target",
+            "rustup not found, can't tell whether the wasm32 target is installed",
+            fix,
+        ),
+    }
+}
+
+fn cargo_metadata(manifest_path: &Path) -> Result<serde_json::Value, String> {
+    let output = Command::new("cargo")
+        .arg("metadata")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .output()
+        .map_err(|err| format!("couldn't run cargo metadata: {err}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|err| err.to_string())
+}
+
+fn project_checks(metadata: &serde_json::Value, package: Option<&str>) -> Vec<Check> {
+    let packages = metadata["packages"].as_array().cloned().unwrap_or_default();
+    let versions_of = |name: &str| {
+        packages
+            .iter()
+            .filter(|p| p["name"] == name)
+            .filter_map(|p| p["version"].as_str().map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+
+    let mut checks = vec![];
+
+    let wasm_bindgen = versions_of("wasm-bindgen");
+    checks.push(if wasm_bindgen.is_empty() {
+        Check::warning(
+            "wasm-bindgen",
+            "the project doesn't depend on wasm-bindgen, the client build will fail",
+            "Add the `hydrate` feature of leptos or depend on wasm-bindgen directly",
+        )
+    } else if wasm_bindgen.iter().all(|v| v == BUNDLED_WASM_BINDGEN) {
+        Check::ok(
+            "wasm-bindgen",
+            format!("{BUNDLED_WASM_BINDGEN} matches the bundled cli"),
+        )
+    } else {
+        Check::error(
+            "wasm-bindgen",
+            format!(
+                "project uses {}, but the bundled cli is {BUNDLED_WASM_BINDGEN}",
+                wasm_bindgen.join(", ")
+            ),
+            format!("cargo update -p wasm-bindgen --precise {BUNDLED_WASM_BINDGEN}"),
+        )
+    });
+
+    for dependency in ["subsecond", "dioxus-devtools"] {
+        let versions = versions_of(dependency);
+        checks.push(if versions.is_empty() {
+            Check::error(
+                dependency,
+                format!("the project doesn't depend on {dependency}, patches can't be applied"),
+                format!("cargo add {dependency}@{DEVTOOLS_VERSION}"),
+            )
+        } else if versions.iter().all(|v| is_compatible(v, DEVTOOLS_VERSION)) {
+            Check::ok(dependency, versions.join(", "))
+        } else {
+            Check::error(
+                dependency,
+                format!(
+                    "project uses {}, which is incompatible with {DEVTOOLS_VERSION}",
+                    versions.join(", ")
+                ),
+                format!("cargo add {dependency}@{DEVTOOLS_VERSION}"),
+            )
+        });
+    }
+
+    if let Some(package) = package
+        && !packages.iter().any(|p| p["name"] == package)
+    {
+        checks.push(Check::error(
+            "package",
+            format!("package {package} not found"),
+            "Pass the name of the package from its Cargo.toml to --package",
+        ));
+    }
+
+    let workspace_root = metadata["workspace_root"]
+        .as_str()
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    checks.push(dev_profile_check(&workspace_root.join("Cargo.toml")));
+
+    checks
+}
+
+/// Patches are resolved against the symbols of the fat binary, so they must not be stripped
+fn dev_profile_check(manifest: &Path) -> Check {
+    const NAME: &str = "dev profile";
+    let contents = match std::fs::read_to_string(manifest) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Check::warning(
+                NAME,
+                format!("couldn't read {}: {err}", manifest.display()),
+                "Make sure [profile.dev] keeps debug symbols",
+            );
+        }
+    };
+    let manifest: toml::Table = match contents.parse() {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            return Check::error(
+                NAME,
+                format!("invalid {}: {err}", manifest.display()),
+                "Fix the syntax of Cargo.toml",
+            );
+        }
+    };
+
+    let Some(dev) = manifest
+        .get("profile")
+        .and_then(|p| p.get("dev"))
+        .and_then(|p| p.as_table())
+    else {
+        return Check::ok(NAME, "default [profile.dev] keeps debug symbols");
+    };
+
+    let no_debug = match dev.get("debug") {
+        Some(toml::Value::Boolean(debug)) => !debug,
+        Some(toml::Value::Integer(level)) => *level == 0,
+        Some(toml::Value::String(level)) => level == "none",
+        _ => false,
+    };
+    let stripped = match dev.get("strip") {
+        Some(toml::Value::Boolean(strip)) => *strip,
+        Some(toml::Value::String(strip)) => strip == "symbols",
+        _ => false,
+    };
+
+    if stripped {
+        Check::error(
+            NAME,
+            "[profile.dev] strips symbols, patches can't be resolved against the fat binary",
+            "Remove `strip` from [profile.dev] or set `strip = \"none\"`",
+        )
+    } else if no_debug {
+        Check::warning(
+            NAME,
+            "[profile.dev] disables debug info",
+            "Remove `debug` from [profile.dev] or set `debug = true`",
+        )
+    } else {
+        Check::ok(NAME, "[profile.dev] keeps debug symbols")
+    }
+}
@@ -0,0 +1,138 @@
+// Structured record of every build, written as JSON lines so patch latency can be tracked over time.
+//
+// Optionally the same builds are written in the Chrome trace event format, which can be opened in
+// `chrome://tracing` or https://ui.perfetto.dev to see the phases of both builders side by side.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    builder::BuildCommand,
+    status::{BuildMetrics, PhaseTiming},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildOutcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildEvent {
+    pub builder: String,
+    pub target: String,
+    pub command: BuildCommand,
+    pub outcome: BuildOutcome,
+    pub started_at_ms: u64,
+    pub elapsed_ms: u64,
+    pub phases: Vec<PhaseTiming>,
+    pub metrics: BuildMetrics,
+    pub error: Option<String>,
+}
+
+pub struct EventLog {
+    jsonl: Option<BufWriter<File>>,
+    chrome_trace: Option<BufWriter<File>>,
+    /// Builders that already got a thread name in the trace
+    named_threads: HashSet<usize>,
+}
+
+impl EventLog {
+    /// Appends to the JSONL file, the Chrome trace is recreated as it is a single JSON array
+    pub fn open(jsonl: Option<&Path>, chrome_trace: Option<&Path>) -> std::io::Result<Self> {
+        let jsonl = match jsonl {
+            Some(path) => Some(BufWriter::new(
+                File::options().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        let chrome_trace = match chrome_trace {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                // the closing bracket is optional in the trace format, so the file stays valid
+                // even if the tool is killed
+                file.write_all(b"[\n")?;
+                file.flush()?;
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Self {
+            jsonl,
+            chrome_trace,
+            named_threads: HashSet::new(),
+        })
+    }
+
+    pub fn record(&mut self, builder_index: usize, event: &BuildEvent) {
+        if let Some(jsonl) = &mut self.jsonl {
+            let res = serde_json::to_writer(&mut *jsonl, event)
+                .map_err(std::io::Error::from)
+                .and_then(|_| jsonl.write_all(b"\n"))
+                .and_then(|_| jsonl.flush());
+            if let Err(err) = res {
+                tracing::warn!("Couldn't write build event: {err}");
+            }
+        }
+
+        if self.chrome_trace.is_some() {
+            let trace_events = self.trace_events(builder_index, event);
+            let chrome_trace = self.chrome_trace.as_mut().unwrap();
+            let res = trace_events
+                .iter()
+                .try_for_each(|e| writeln!(chrome_trace, "{e},"))
+                .and_then(|_| chrome_trace.flush());
+            if let Err(err) = res {
+                tracing::warn!("Couldn't write trace event: {err}");
+            }
+        }
+    }
+
+    /// One complete ("X") event for the build and one for each of its phases, on the builder's thread
+    fn trace_events(&mut self, tid: usize, event: &BuildEvent) -> Vec<serde_json::Value> {
+        let mut trace_events = vec![];
+        if self.named_threads.insert(tid) {
+            trace_events.push(serde_json::json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": tid,
+                "args": { "name": format!("{} ({})", event.builder, event.target) },
+            }));
+        }
+        trace_events.push(serde_json::json!({
+            "name": format!("{:?}", event.command).to_lowercase(),
+            "cat": "build",
+            "ph": "X",
+            "pid": 1,
+            "tid": tid,
+            "ts": event.started_at_ms * 1000,
+            "dur": event.elapsed_ms * 1000,
+            "args": {
+                "outcome": event.outcome,
+                "metrics": event.metrics,
+                "error": event.error,
+            },
+        }));
+        for phase in &event.phases {
+            trace_events.push(serde_json::json!({
+                "name": phase.phase.to_string(),
+                "cat": "phase",
+                "ph": "X",
+                "pid": 1,
+                "tid": tid,
+                "ts": phase.started_at_ms * 1000,
+                "dur": phase.elapsed_ms * 1000,
+            }));
+        }
+        trace_events
+    }
+}
@@ -2,14 +2,32 @@ use std::path::Path;
 use std::time::Instant;
 use std::{path::PathBuf, process::Command};
 
+use anyhow::bail;
 use itertools::Itertools;
 use target_lexicon::OperatingSystem;
 use uuid::Uuid;
 
+use crate::cancel::CancelToken;
 use crate::context::Context;
+use crate::fingerprint::objects_fingerprint;
+use crate::status::{BuildPhase, BuilderHandle};
+use crate::workspace;
 use crate::{LinkerFlavor, RustcArgs};
 
-fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
+pub struct FatArtifacts {
+    /// Final executable, or the bindgened wasm module for the web
+    pub exe: PathBuf,
+    /// Fingerprint of the top-level crate objects, see [`objects_fingerprint`]
+    pub objects_fingerprint: u64,
+}
+
+/// Returns the fingerprint of the top-level crate objects since they are removed after linking
+fn fat_link(
+    ctx: &Context,
+    exe: &Path,
+    rustc_args: &RustcArgs,
+    cancel: &CancelToken,
+) -> anyhow::Result<u64> {
     // Filter out the rlib files from the arguments
     let rlibs = rustc_args
         .link_args
@@ -140,6 +158,8 @@ fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
         //         _ = Command::new(ranlib).arg(&out_ar_path).output();
         //     }
         // }
+    } else {
+        crate::gc::mark_fat_archive_used(&out_ar_path);
     }
 
     compiler_rlibs.dedup();
@@ -149,50 +169,50 @@ fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
     //
     // We also need to insert the -force_load flag to force the linker to load the archive
     let mut args: Vec<_> = rustc_args.link_args.iter().skip(1).cloned().collect();
-    if let Some(last_object) = args.iter().rposition(|arg| arg.ends_with(".o")) {
-        if archive_has_contents {
-            match ctx.linker_flavor() {
-                LinkerFlavor::WasmLld => {
-                    args.insert(last_object, "--whole-archive".to_string());
-                    args.insert(last_object + 1, out_ar_path.display().to_string());
-                    args.insert(last_object + 2, "--no-whole-archive".to_string());
-                    args.retain(|arg| !arg.ends_with(".rlib"));
-                    for rlib in compiler_rlibs.iter().rev() {
-                        args.insert(last_object + 3, rlib.display().to_string());
-                    }
-                }
-                LinkerFlavor::Gnu => {
-                    args.insert(last_object, "-Wl,--whole-archive".to_string());
-                    args.insert(last_object + 1, out_ar_path.display().to_string());
-                    args.insert(last_object + 2, "-Wl,--no-whole-archive".to_string());
-                    args.retain(|arg| !arg.ends_with(".rlib"));
-                    for rlib in compiler_rlibs.iter().rev() {
-                        args.insert(last_object + 3, rlib.display().to_string());
-                    }
+    if let Some(last_object) = args.iter().rposition(|arg| arg.ends_with(".o"))
+        && archive_has_contents
+    {
+        match ctx.linker_flavor() {
+            LinkerFlavor::WasmLld => {
+                args.insert(last_object, "--whole-archive".to_string());
+                args.insert(last_object + 1, out_ar_path.display().to_string());
+                args.insert(last_object + 2, "--no-whole-archive".to_string());
+                args.retain(|arg| !arg.ends_with(".rlib"));
+                for rlib in compiler_rlibs.iter().rev() {
+                    args.insert(last_object + 3, rlib.display().to_string());
                 }
-                LinkerFlavor::Darwin => {
-                    args.insert(last_object, "-Wl,-force_load".to_string());
-                    args.insert(last_object + 1, out_ar_path.display().to_string());
-                    args.retain(|arg| !arg.ends_with(".rlib"));
-                    for rlib in compiler_rlibs.iter().rev() {
-                        args.insert(last_object + 2, rlib.display().to_string());
-                    }
+            }
+            LinkerFlavor::Gnu => {
+                args.insert(last_object, "-Wl,--whole-archive".to_string());
+                args.insert(last_object + 1, out_ar_path.display().to_string());
+                args.insert(last_object + 2, "-Wl,--no-whole-archive".to_string());
+                args.retain(|arg| !arg.ends_with(".rlib"));
+                for rlib in compiler_rlibs.iter().rev() {
+                    args.insert(last_object + 3, rlib.display().to_string());
                 }
-                LinkerFlavor::Msvc => {
-                    args.insert(
-                        last_object,
-                        format!("/WHOLEARCHIVE:{}", out_ar_path.display()),
-                    );
-                    args.retain(|arg| !arg.ends_with(".rlib"));
-                    for rlib in compiler_rlibs.iter().rev() {
-                        args.insert(last_object + 1, rlib.display().to_string());
-                    }
+            }
+            LinkerFlavor::Darwin => {
+                args.insert(last_object, "-Wl,-force_load".to_string());
+                args.insert(last_object + 1, out_ar_path.display().to_string());
+                args.retain(|arg| !arg.ends_with(".rlib"));
+                for rlib in compiler_rlibs.iter().rev() {
+                    args.insert(last_object + 2, rlib.display().to_string());
                 }
-                LinkerFlavor::Unsupported => {
-                    tracing::error!("Unsupported platform for fat linking");
+            }
+            LinkerFlavor::Msvc => {
+                args.insert(
+                    last_object,
+                    format!("/WHOLEARCHIVE:{}", out_ar_path.display()),
+                );
+                args.retain(|arg| !arg.ends_with(".rlib"));
+                for rlib in compiler_rlibs.iter().rev() {
+                    args.insert(last_object + 1, rlib.display().to_string());
                 }
-            };
-        }
+            }
+            LinkerFlavor::Unsupported => {
+                tracing::error!("Unsupported platform for fat linking");
+            }
+        };
     }
 
     // Add custom args to the linkers
@@ -261,12 +281,12 @@ fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
     // }
 
     // Run the linker directly!
-    let res = Command::new(linker)
-        .args(out_args)
-        .env_clear()
-        .envs(rustc_args.envs.iter().map(|(k, v)| (k, v)))
-        .output()
-        .unwrap();
+    let res = cancel.output(
+        Command::new(linker)
+            .args(out_args)
+            .env_clear()
+            .envs(rustc_args.envs.iter().map(|(k, v)| (k, v))),
+    )?;
 
     if !res.stderr.is_empty() {
         let errs = String::from_utf8_lossy(&res.stderr);
@@ -282,8 +302,15 @@ fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
         tracing::trace!("Output from fat linking: {}", out.trim());
     }
 
+    let objects = args
+        .iter()
+        .filter(|arg| arg.ends_with(".rcgu.o"))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    let fingerprint = objects_fingerprint(&objects);
+
     // Clean up the temps manually
-    for f in args.iter().filter(|arg| arg.ends_with(".rcgu.o")) {
+    for f in objects {
         _ = std::fs::remove_file(f);
     }
 
@@ -295,9 +322,14 @@ fn fat_link(ctx: &Context, exe: &Path, rustc_args: &RustcArgs) {
             .map(|s| s.display().to_string())
             .join("\n"),
     );
+
+    crate::gc::prune_fat_archives(exe.parent().unwrap(), &out_ar_path, ctx.keep_fat_archives);
+
+    Ok(fingerprint)
 }
 
-fn clean_fingerprint(ctx: &Context) {
+/// Makes cargo compile `package` again
+pub fn clean_fingerprint(ctx: &Context, package: &str) {
     // `dx` compiles everything with `--target` which ends up with a structure like:
     // target/<triple>/<profile>/.fingerprint/<package_name>-<hash>
     //
@@ -311,30 +343,37 @@ fn clean_fingerprint(ctx: &Context) {
 
     if let Ok(dirs) = std::fs::read_dir(&fingerprint_dir) {
         for entry in dirs.flatten() {
-            if let Some(fname) = entry.file_name().to_str() {
-                if let Some((name, _)) = fname.rsplit_once('-') {
-                    if name == ctx.package {
-                        _ = std::fs::remove_dir_all(entry.path());
-                    }
-                }
+            if let Some(fname) = entry.file_name().to_str()
+                && let Some((name, _)) = fname.rsplit_once('-')
+                && name == package
+            {
+                _ = std::fs::remove_dir_all(entry.path());
             }
         }
     }
 }
 
-pub fn build_fat(ctx: &Context) -> PathBuf {
+pub fn build_fat(
+    ctx: &Context,
+    cancel: &CancelToken,
+    status: &BuilderHandle,
+) -> anyhow::Result<FatArtifacts> {
     tracing::debug!("Fat build started");
     let time_start = Instant::now();
 
-    clean_fingerprint(ctx);
+    clean_fingerprint(ctx, &ctx.package);
 
     let mut cmd = build_fat_command(ctx);
-    let mut process = cmd.spawn().unwrap();
-    process.wait().unwrap();
+    // record the rustc invocations of workspace members for thin builds
+    cmd.envs(workspace::capture_envs(ctx, "dx"));
+    let exit_status = status.run(cancel, &mut cmd)?;
+    if !exit_status.success() {
+        bail!("cargo exited with {exit_status}");
+    }
 
     let compiled_exe = ctx
         .target_triple_profile_dir()
-        .join(&ctx.final_binary_name());
+        .join(ctx.final_binary_name());
 
     let mut rustc_args: RustcArgs =
         serde_json::from_str(&std::fs::read_to_string(ctx.rustc_wrapper_file.path()).unwrap())
@@ -345,9 +384,13 @@ pub fn build_fat(ctx: &Context) -> PathBuf {
         .map(|s| s.to_string())
         .collect::<Vec<_>>();
 
-    fat_link(ctx, &compiled_exe, &rustc_args);
+    status.set_phase(BuildPhase::Linking);
+    let objects_fingerprint = fat_link(ctx, &compiled_exe, &rustc_args, cancel)?;
 
-    let bundle_exe = ctx.write_executable(&compiled_exe);
+    if ctx.is_web() {
+        status.set_phase(BuildPhase::Bindgen);
+    }
+    let bundle_exe = ctx.write_executable(&compiled_exe)?;
     // TODO: write frameworks
 
     tracing::debug!(
@@ -355,12 +398,16 @@ pub fn build_fat(ctx: &Context) -> PathBuf {
         time_start.elapsed().as_secs_f32()
     );
 
-    bundle_exe
+    Ok(FatArtifacts {
+        exe: bundle_exe,
+        objects_fingerprint,
+    })
 }
 
 pub fn build_fat_command(ctx: &Context) -> Command {
     let mut command = Command::new("cargo");
     command
+        .envs(ctx.build_envs())
         .env(
             "DX_RUSTC",
             ctx.rustc_wrapper_file
@@ -408,6 +455,9 @@ pub fn build_fat_command(ctx: &Context) -> Command {
         }
     }
 
+    // diagnostics are kept for the control API, see `BuilderHandle::run`
+    command.arg("--message-format=json-diagnostic-rendered-ansi");
+
     command.arg("--").arg("-Clinker=dx");
     if ctx.triple.operating_system == OperatingSystem::Linux {
         command
@@ -0,0 +1,112 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+/// Build ids end up in JSON read by javascript, keep them exact as a JS number
+const BUILD_ID_MASK: u64 = (1 << 53) - 1;
+
+/// Hash of the object files produced by rustc for the top-level crate.
+///
+/// Codegen unit names are derived from the crate contents, so hashing names and contents of every
+/// `.rcgu.o` is enough to tell whether the code of the crate changed between two compilations. For
+/// example, a change inside `#[cfg(feature = "ssr")]` code leaves the `hydrate` objects untouched.
+pub fn objects_fingerprint(objects: &[PathBuf]) -> u64 {
+    let mut objects = objects.to_vec();
+    objects.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    // `DefaultHasher::new` uses fixed keys so hashes are comparable across builds
+    let mut hasher = DefaultHasher::new();
+    for object in &objects {
+        object.file_name().hash(&mut hasher);
+        match std::fs::read(object) {
+            Ok(contents) => contents.hash(&mut hasher),
+            // unreadable objects are treated as always changed
+            Err(_) => std::time::SystemTime::now().hash(&mut hasher),
+        }
+    }
+    hasher.finish()
+}
+
+/// Fingerprint of the objects the clients run, see [`objects_fingerprint`]. The fingerprint of a
+/// prepared patch only becomes current once the patch is sent, a cancelled patch has to be built
+/// again even though its code didn't change.
+#[derive(Debug, Default)]
+pub struct SentObjects {
+    sent: Option<u64>,
+    prepared: Option<u64>,
+}
+
+impl SentObjects {
+    /// After a fat build, clients run its objects
+    pub fn reset(&mut self, fingerprint: u64) {
+        self.sent = Some(fingerprint);
+        self.prepared = None;
+    }
+
+    /// Whether clients already run objects with this fingerprint
+    pub fn is_sent(&self, fingerprint: u64) -> bool {
+        self.sent == Some(fingerprint)
+    }
+
+    /// A patch of these objects was linked
+    pub fn prepared(&mut self, fingerprint: u64) {
+        self.prepared = Some(fingerprint);
+    }
+
+    /// The prepared patch was sent to clients
+    pub fn sent(&mut self) {
+        if let Some(fingerprint) = self.prepared.take() {
+            self.sent = Some(fingerprint);
+        }
+    }
+
+    /// The prepared patch was thrown away, e.g. because the patch of the other side failed
+    pub fn discarded(&mut self) {
+        self.prepared = None;
+    }
+}
+
+/// Id of a fat build, derived from the contents of the fat binary. Patches are stamped with it so
+/// clients still running a previous fat build never apply them.
+pub fn build_id(exe: &Path) -> std::io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    std::fs::read(exe)?.hash(&mut hasher);
+    Ok(hasher.finish() & BUILD_ID_MASK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discarded_patch_is_built_again() {
+        let (mut backend, mut frontend) = (SentObjects::default(), SentObjects::default());
+        backend.reset(1);
+        frontend.reset(10);
+
+        // both sides changed, the client patch fails and the server patch is thrown away
+        backend.prepared(2);
+        backend.discarded();
+        assert!(!backend.is_sent(2));
+
+        // the rebuild of the same code patches both sides
+        assert!(!backend.is_sent(2));
+        assert!(!frontend.is_sent(11));
+        backend.prepared(2);
+        frontend.prepared(11);
+        backend.sent();
+        frontend.sent();
+        assert!(backend.is_sent(2));
+        assert!(frontend.is_sent(11));
+    }
+
+    #[test]
+    fn cancelled_patch_is_not_current() {
+        let mut objects = SentObjects::default();
+        objects.reset(1);
+        objects.prepared(2);
+        assert!(!objects.is_sent(2));
+        assert!(objects.is_sent(1));
+    }
+}
@@ -0,0 +1,173 @@
+// Removal of hot-patch artifacts that no client can use anymore.
+//
+// - patch libraries `lib<name>-patch-<millis>.<ext>` (and their copies in the site pkg dir) are only
+//   loadable by the fat build they were linked against, so they are all removed once a new fat build
+//   of the same binary succeeds. Patches that were built but never sent are removed right away.
+// - fat archives `libdeps-<hash>.a` with their `rlibs-<hash>.txt` are created for every new set of
+//   dependencies, only the one in use and a few recent ones are kept to speed up switching back.
+//
+// `clean` removes all of them at once.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use target_lexicon::Triple;
+
+use crate::context::Context;
+
+pub const DEFAULT_KEEP_FAT_ARCHIVES: usize = 2;
+
+#[derive(Default)]
+struct Removed {
+    files: usize,
+    bytes: u64,
+}
+
+impl Removed {
+    fn remove(&mut self, path: &Path) {
+        let len = path.metadata().map(|m| m.len()).unwrap_or(0);
+        if std::fs::remove_file(path).is_ok() {
+            self.files += 1;
+            self.bytes += len;
+        }
+    }
+
+    fn log(&self, what: &str) {
+        if self.files > 0 {
+            tracing::debug!(
+                "Removed {} {what} ({:.1} MB)",
+                self.files,
+                self.bytes as f64 / 1_000_000.0
+            );
+        }
+    }
+}
+
+fn is_patch_of(file_name: &str, binary_name: &str) -> bool {
+    file_name
+        .strip_prefix(&format!("lib{binary_name}-patch-"))
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|e| e.path()).collect())
+        .unwrap_or_default()
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// Removes every patch library of the binary built by `ctx`, called after a fat build since none
+/// of them can be applied to the new binary
+pub fn remove_patches(ctx: &Context) {
+    let binary_name = ctx.final_binary_name();
+    let mut removed = Removed::default();
+    for dir in [ctx.target_triple_profile_dir(), ctx.pkg_out_path()] {
+        for path in files_in(&dir) {
+            if is_patch_of(file_name(&path), &binary_name) {
+                removed.remove(&path);
+            }
+        }
+    }
+    removed.log("patches of the previous fat build");
+}
+
+/// Removes a patch library that was never sent to clients
+pub fn remove_patch(ctx: &Context, lib: &Path) {
+    let Some(name) = lib.file_name() else {
+        return;
+    };
+    let mut removed = Removed::default();
+    removed.remove(&ctx.target_triple_profile_dir().join(name));
+    removed.remove(&ctx.pkg_out_path().join(name));
+    // wasm-bindgen shim of a wasm patch
+    let shim = Path::new(name).with_extension("js");
+    removed.remove(&ctx.target_triple_profile_dir().join(&shim));
+    removed.remove(&ctx.pkg_out_path().join(&shim));
+    removed.log("unsent patch");
+}
+
+/// Bumps the modification time of a reused fat archive, which [`prune_fat_archives`] orders by
+pub fn mark_fat_archive_used(archive: &Path) {
+    let touched = std::fs::File::options()
+        .append(true)
+        .open(archive)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = touched {
+        tracing::debug!("Couldn't touch {}: {err}", archive.display());
+    }
+}
+
+/// Keeps the fat archive `in_use` and the `keep - 1` most recently used other ones in `dir`
+pub fn prune_fat_archives(dir: &Path, in_use: &Path, keep: usize) {
+    let mut archives = files_in(dir)
+        .into_iter()
+        .filter(|path| {
+            let name = file_name(path);
+            name.starts_with("libdeps-") && name.ends_with(".a") && path != in_use
+        })
+        .map(|path| {
+            let modified = path
+                .metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, path)
+        })
+        .collect::<Vec<_>>();
+    archives.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    let mut removed = Removed::default();
+    for (_, archive) in archives.into_iter().skip(keep.saturating_sub(1)) {
+        removed.remove(&archive);
+        let hash = file_name(&archive)
+            .trim_start_matches("libdeps-")
+            .trim_end_matches(".a")
+            .to_string();
+        removed.remove(&archive.with_file_name(format!("rlibs-{hash}.txt")));
+    }
+    removed.log("old fat archives");
+}
+
+/// Removes all hot-patch artifacts: patches of `binary_names`, fat archives, rlib lists and symbol
+/// stubs in the profile dirs of every target in `target_dir`, and patch copies in `site_pkg_dir`
+pub fn clean(target_dir: &Path, site_pkg_dir: &Path, binary_names: &[String]) {
+    let mut removed = Removed::default();
+    let is_patch = |name: &str| binary_names.iter().any(|binary| is_patch_of(name, binary));
+    let is_artifact = |name: &str| {
+        is_patch(name)
+            || (name.starts_with("libdeps-") && name.ends_with(".a"))
+            || (name.starts_with("rlibs-") && name.ends_with(".txt"))
+            || name == "stub.o"
+    };
+
+    // target/<triple>/<profile>/, cargo keeps a `.fingerprint` dir in every profile dir
+    let profile_dirs = files_in(target_dir)
+        .into_iter()
+        .filter(|path| path.is_dir() && file_name(path).parse::<Triple>().is_ok())
+        .flat_map(|triple_dir| files_in(&triple_dir))
+        .filter(|path| path.join(".fingerprint").is_dir());
+    for dir in profile_dirs {
+        for path in files_in(&dir) {
+            if path.is_file() && is_artifact(file_name(&path)) {
+                removed.remove(&path);
+            }
+        }
+    }
+    for path in files_in(site_pkg_dir) {
+        if is_patch(file_name(&path)) {
+            removed.remove(&path);
+        }
+    }
+
+    println!(
+        "Removed {} hot-patch artifacts ({:.1} MB)",
+        removed.files,
+        removed.bytes as f64 / 1_000_000.0
+    );
+}
@@ -0,0 +1,92 @@
+// Content-hashed file names for the site pkg, compatible with Leptos `hash-files`.
+//
+// Files are named `<output-name>.<hash>.<ext>` and `hash.txt` next to the server binary lists the
+// hash of every kind of file:
+//
+// js: <hash>
+// wasm: <hash>
+//
+// Leptos reads it when the server runs with `LEPTOS_HASH_FILES=true`. `manifest.json` in the pkg dir
+// maps the same kinds to the file names, for servers and tools that don't use Leptos:
+//
+// { "js": "app.<hash>.js", "wasm": "app.<hash>.wasm" }
+
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::Path,
+};
+
+pub const HASH_FILE_NAME: &str = "hash.txt";
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// `DefaultHasher::new` uses fixed keys, so the same contents always get the same name
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Hashed files written to a pkg dir, by extension
+#[derive(Debug, Default)]
+pub struct HashedFiles {
+    pub hashes: BTreeMap<String, String>,
+    pub files: BTreeMap<String, String>,
+}
+
+impl HashedFiles {
+    /// Writes `bytes` into `dir` as `<name>.<hash>.<ext>`, returns the file name
+    pub fn write(
+        &mut self,
+        dir: &Path,
+        name: &str,
+        ext: &str,
+        bytes: &[u8],
+    ) -> std::io::Result<String> {
+        let hash = content_hash(bytes);
+        let file_name = format!("{name}.{hash}.{ext}");
+        std::fs::write(dir.join(&file_name), bytes)?;
+        self.hashes.insert(ext.to_string(), hash);
+        self.files.insert(ext.to_string(), file_name.clone());
+        Ok(file_name)
+    }
+
+    /// Writes `hash.txt` into `dir`, the directory of the server binary
+    pub fn write_hash_txt(&self, dir: &Path) -> std::io::Result<()> {
+        let contents = self
+            .hashes
+            .iter()
+            .map(|(ext, hash)| format!("{ext}: {hash}\n"))
+            .collect::<String>();
+        std::fs::write(dir.join(HASH_FILE_NAME), contents)
+    }
+
+    /// Writes `manifest.json` into `dir`, the pkg dir
+    pub fn write_manifest(&self, dir: &Path) -> std::io::Result<()> {
+        std::fs::write(
+            dir.join(MANIFEST_FILE_NAME),
+            serde_json::to_vec_pretty(&self.files)?,
+        )
+    }
+
+    /// Removes the files of previous builds named `<name>.<hash>.<ext>` from `dir`, a browser that
+    /// still asks for them gets a 404 instead of stale code
+    pub fn remove_stale(&self, dir: &Path, name: &str) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some((hash, ext)) = file_name
+                .strip_prefix(&format!("{name}."))
+                .and_then(|rest| rest.split_once('.'))
+            else {
+                continue;
+            };
+            let is_hash = hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+            if is_hash && self.files.get(ext) != Some(&file_name) {
+                _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Bare minimum of HTTP/1.1 used by the proxy and the control API, one request per connection.
+
+use std::io::{BufRead, Write};
+
+pub struct HttpHead {
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpHead {
+    pub fn read(reader: &mut impl BufRead) -> Option<Self> {
+        let mut start_line = String::new();
+        if reader.read_line(&mut start_line).ok()? == 0 {
+            return None;
+        }
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        Some(Self {
+            start_line: start_line.trim_end().to_string(),
+            headers,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.start_line.clone();
+        out.push_str("\r\n");
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+
+    pub fn method(&self) -> &str {
+        self.start_line.split(' ').next().unwrap_or("GET")
+    }
+
+    pub fn path(&self) -> &str {
+        self.start_line.split(' ').nth(1).unwrap_or("/")
+    }
+
+    /// Status code of a response head
+    pub fn status_code(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+
+    /// Whether a response to the request `request` with this head never has a body
+    pub fn is_bodyless_response(&self, request: &HttpHead) -> bool {
+        request.method().eq_ignore_ascii_case("HEAD")
+            || self
+                .status_code()
+                .is_some_and(|code| (100..200).contains(&code) || code == 204 || code == 304)
+    }
+
+    /// Whether a request is a page navigation rather than a fetch of an asset or API
+    pub fn is_navigation(&self) -> bool {
+        match self.header("sec-fetch-mode") {
+            Some(mode) => mode.eq_ignore_ascii_case("navigate"),
+            None => self
+                .header("accept")
+                .is_some_and(|v| v.contains("text/html")),
+        }
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade").is_some()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"))
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.header("content-length").and_then(|v| v.parse().ok())
+    }
+}
+
+/// Reads a message body according to its headers, decoding chunked encoding.
+/// Responses without length are read until the connection is closed.
+pub fn read_body(
+    reader: &mut impl BufRead,
+    head: &HttpHead,
+    until_eof: bool,
+) -> std::io::Result<Vec<u8>> {
+    let mut body = vec![];
+    if head.is_chunked() {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_str = size_line.trim().split(';').next().unwrap_or("0");
+            let size = usize::from_str_radix(size_str, 16).unwrap_or(0);
+            if size == 0 {
+                // skip trailers
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            let mut chunk = vec![0; size];
+            reader.read_exact(&mut chunk)?;
+            body.extend(chunk);
+            let mut crlf = String::new();
+            reader.read_line(&mut crlf)?;
+        }
+    } else if let Some(len) = head.content_length() {
+        body.resize(len, 0);
+        reader.read_exact(&mut body)?;
+    } else if until_eof {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok(body)
+}
+
+pub fn write_response(
+    writer: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    writer.write_all(body)
+}
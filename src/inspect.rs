@@ -0,0 +1,329 @@
+// `inspect` subcommand: looks inside the fat binary and patches the same way the patcher does.
+//
+// - `symbols` searches the symbol table loaded into `HotpatchModuleCache`
+// - `patch` prints the jump table `create_jump_table` builds for a patch
+// - `explain` tells why undefined symbols of a patch can or can't be resolved against the fat binary
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use object::{Object, ObjectSymbol};
+use rustc_demangle::demangle;
+use target_lexicon::Triple;
+use walrus::Module;
+
+use crate::patch::{
+    HotpatchModuleCache, create_jump_table, is_wasm, main_sentinel, name_is_bindgen_symbol,
+    wasm_data_symbols,
+};
+
+/// Demangled name without the hash suffix
+fn pretty(name: &str) -> String {
+    format!("{:#}", demangle(name))
+}
+
+/// Wasm modules are recognized by their extension, everything else is assumed to be for the host
+pub fn default_triple(fat: &Path) -> Triple {
+    if fat.extension().is_some_and(|ext| ext == "wasm") {
+        "wasm32-unknown-unknown".parse().unwrap()
+    } else {
+        Triple::host()
+    }
+}
+
+fn load_cache(fat: &Path, triple: &Triple) -> anyhow::Result<HotpatchModuleCache> {
+    HotpatchModuleCache::new(fat, triple)
+        .with_context(|| format!("failed to load {}", fat.display()))
+}
+
+fn matches(name: &str, query: &str) -> bool {
+    let query = query.to_lowercase();
+    name.to_lowercase().contains(&query) || pretty(name).to_lowercase().contains(&query)
+}
+
+/// Prints every symbol of the fat binary whose mangled or demangled name contains `query`
+pub fn symbols(fat: &Path, triple: &Triple, query: &str, limit: usize) -> anyhow::Result<()> {
+    let cache = load_cache(fat, triple)?;
+
+    if is_wasm(triple) {
+        let mut found = cache
+            .symbol_ifunc_map
+            .iter()
+            .filter(|(name, _)| matches(name, query))
+            .collect::<Vec<_>>();
+        found.sort_by_key(|(_, index)| **index);
+        println!("{} matching symbols in the ifunc table", found.len());
+        for (name, index) in found.iter().take(limit) {
+            println!(
+                "{}\n    mangled: {name}\n    ifunc index: {index}",
+                pretty(name)
+            );
+        }
+        return Ok(());
+    }
+
+    let mut found = cache
+        .symbol_table
+        .iter()
+        .filter(|(name, _)| matches(name, query))
+        .collect::<Vec<_>>();
+    found.sort_by_key(|(_, symbol)| symbol.address);
+    println!("{} matching symbols", found.len());
+    for (name, symbol) in found.iter().take(limit) {
+        println!(
+            "{}\n    mangled: {name}\n    address: {:#x}  kind: {:?}  size: {}  weak: {}  undefined: {}",
+            pretty(name),
+            symbol.address,
+            symbol.kind,
+            symbol.size,
+            symbol.is_weak,
+            symbol.is_undefined,
+        );
+    }
+    Ok(())
+}
+
+/// Prints the jump table of `patch` with the names of the replaced and replacing functions
+pub fn patch(fat: &Path, patch: &Path, triple: &Triple) -> anyhow::Result<()> {
+    let cache = load_cache(fat, triple)?;
+
+    // creating a wasm jump table rewrites the patch, work on a copy
+    let copy = tempfile::Builder::new()
+        .suffix(&format!(
+            ".{}",
+            patch.extension().unwrap_or_default().to_string_lossy()
+        ))
+        .tempfile()?;
+    std::fs::copy(patch, copy.path())?;
+    let jump_table = create_jump_table(copy.path(), triple, &cache)?;
+
+    let (old_names, new_names) = if is_wasm(triple) {
+        let old_names = cache
+            .symbol_ifunc_map
+            .iter()
+            .map(|(name, index)| (*index as u64, name.clone()))
+            .collect::<HashMap<_, _>>();
+        (old_names, HashMap::new())
+    } else {
+        let old_names = cache
+            .symbol_table
+            .iter()
+            .filter(|(_, symbol)| !symbol.is_undefined)
+            .map(|(name, symbol)| (symbol.address, name.clone()))
+            .collect::<HashMap<_, _>>();
+        let bytes = std::fs::read(patch)?;
+        let file = object::File::parse(&bytes as &[u8])?;
+        let new_names = file
+            .symbols()
+            .filter_map(|s| Some((s.address(), s.name().ok()?.to_string())))
+            .collect::<HashMap<_, _>>();
+        (old_names, new_names)
+    };
+
+    println!(
+        "aslr reference: {:#x}\nnew base address: {:#x}\nifunc count: {}\n{} replaced functions",
+        jump_table.aslr_reference,
+        jump_table.new_base_address,
+        jump_table.ifunc_count,
+        jump_table.map.len()
+    );
+    let mut entries = jump_table.map.iter().collect::<Vec<_>>();
+    entries.sort();
+    for (old, new) in entries {
+        let old_name = old_names.get(old).map(|n| pretty(n)).unwrap_or_default();
+        let new_name = new_names
+            .get(new)
+            .map(|n| pretty(n))
+            .unwrap_or_else(|| old_name.clone());
+        println!("{old:#x} -> {new:#x}  {old_name}");
+        if new_name != old_name {
+            println!("    now {new_name}");
+        }
+    }
+    Ok(())
+}
+
+/// Explains how `symbol`, or every undefined symbol of `patch` if no symbol is given, is resolved
+/// against the fat binary
+pub fn explain(
+    fat: &Path,
+    patch: Option<&PathBuf>,
+    symbol: Option<&str>,
+    triple: &Triple,
+) -> anyhow::Result<()> {
+    let cache = load_cache(fat, triple)?;
+    let wasm = is_wasm(triple);
+
+    // (import module, name), the module is only meaningful for wasm
+    let undefined = match (symbol, patch) {
+        (Some(symbol), _) => vec![(String::new(), symbol.to_string())],
+        (None, Some(patch)) if wasm => {
+            let module = Module::from_file(patch)?;
+            module
+                .imports
+                .iter()
+                .map(|import| (import.module.clone(), import.name.clone()))
+                .collect()
+        }
+        (None, Some(patch)) => {
+            let bytes = std::fs::read(patch)?;
+            let file = object::File::parse(&bytes as &[u8])?;
+            file.symbols()
+                .filter(|s| s.is_undefined())
+                .filter_map(|s| Some((String::new(), s.name().ok()?.to_string())))
+                .collect()
+        }
+        (None, None) => anyhow::bail!("pass a symbol or --patch to explain"),
+    };
+
+    let data_symbols = if wasm {
+        wasm_data_symbols(&cache.old_bytes)?
+    } else {
+        Default::default()
+    };
+
+    let mut unresolved = 0;
+    for (module, name) in &undefined {
+        let explanation = if wasm {
+            explain_wasm(&cache, &data_symbols, module, name)
+        } else {
+            explain_native(&cache, triple, name)
+        };
+        let resolved = explanation.is_ok();
+        if !resolved {
+            unresolved += 1;
+        }
+        // with a whole patch only the problems are interesting
+        if symbol.is_none() && resolved {
+            continue;
+        }
+        let prefix = if module.is_empty() {
+            String::new()
+        } else {
+            format!("{module}.")
+        };
+        println!("{prefix}{}\n    mangled: {name}", pretty(name));
+        match explanation {
+            Ok(msg) => println!("    resolved: {msg}"),
+            Err(msg) => println!("    unresolved: {msg}"),
+        }
+        for similar in similar_symbols(&cache, name).iter().take(5) {
+            println!("    similar symbol in fat binary: {}", pretty(similar));
+        }
+    }
+    if symbol.is_none() {
+        println!(
+            "{} undefined symbols, {unresolved} can't be resolved",
+            undefined.len()
+        );
+    }
+    Ok(())
+}
+
+/// Mirrors the lookup done by `create_undefined_symbol_stub`
+fn explain_native(
+    cache: &HotpatchModuleCache,
+    triple: &Triple,
+    name: &str,
+) -> Result<String, String> {
+    let lookup = name.trim_start_matches("__imp_");
+    let found = cache.symbol_table.get(lookup).or_else(|| {
+        // symbols in darwin objects carry an extra leading underscore
+        lookup
+            .strip_prefix('_')
+            .and_then(|name| cache.symbol_table.get(name))
+    });
+
+    match found {
+        None if name == main_sentinel(triple) => Ok("entry point of the patch".to_string()),
+        None => Err(
+            "not in the symbol table of the fat binary. The patch references code the fat build \
+             didn't contain: a generic instantiation or a dependency function that was never used \
+             before, or the dependencies changed. Do a fat rebuild"
+                .to_string(),
+        ),
+        Some(symbol) if symbol.is_undefined => Err(
+            "the fat binary imports it from a shared library itself, so it has no address to \
+             point the patch at. Make sure the library is linked into the patch or do a fat rebuild"
+                .to_string(),
+        ),
+        Some(symbol) => Ok(format!(
+            "{:?} at {:#x} in the fat binary (size {}{})",
+            symbol.kind,
+            symbol.address,
+            symbol.size,
+            if symbol.is_weak { ", weak" } else { "" }
+        )),
+    }
+}
+
+/// Mirrors the import handling of `create_wasm_jump_table`
+fn explain_wasm(
+    cache: &HotpatchModuleCache,
+    data_symbols: &HashSet<String>,
+    module: &str,
+    name: &str,
+) -> Result<String, String> {
+    let ifunc = cache.symbol_ifunc_map.get(name);
+    match module {
+        "GOT.func" => ifunc
+            .map(|index| format!("ifunc table index {index}"))
+            .ok_or_else(|| {
+                "function is not in the indirect function table of the fat module. It didn't exist \
+                 in the fat build or was merged with another function, do a fat rebuild"
+                    .to_string()
+            }),
+        "GOT.mem" if data_symbols.contains(name) => Ok("data symbol of the fat module".to_string()),
+        "GOT.mem" => Err(
+            "data symbol is not in the symbol table of the fat module. It's new static data or the \
+             names were optimized away, check the dev profile opt-level or do a fat rebuild"
+                .to_string(),
+        ),
+        "__wbindgen_placeholder__" => {
+            let saved = format!("__saved_wbg_{name}");
+            if cache.old_exports.contains(&saved) || name_is_bindgen_symbol(&saved) {
+                Ok(format!("wasm-bindgen import saved as {saved}"))
+            } else {
+                Ok(
+                    "new wasm-bindgen import, satisfied by the JS shim generated for the patch as \
+                     long as it only passes numbers and strings, otherwise do a fat rebuild"
+                        .to_string(),
+                )
+            }
+        }
+        _ if cache.old_exports.contains(name) || cache.old_imports.contains(name) => {
+            Ok("provided by the fat module".to_string())
+        }
+        _ => match ifunc {
+            Some(index) => Ok(format!("called through ifunc table index {index}")),
+            None if name_is_bindgen_symbol(name) => Ok("wasm-bindgen describe stub".to_string()),
+            None => Err(
+                "neither exported by the fat module nor in its indirect function table, do a fat \
+                 rebuild"
+                    .to_string(),
+            ),
+        },
+    }
+}
+
+/// Symbols of the fat binary whose demangled path ends the same way, useful when a generic
+/// instantiation or a hash changed
+fn similar_symbols(cache: &HotpatchModuleCache, name: &str) -> Vec<String> {
+    let pretty_name = pretty(name);
+    let Some(last) = pretty_name.rsplit("::").next().filter(|l| l.len() > 2) else {
+        return vec![];
+    };
+    let mut similar = cache
+        .symbol_table
+        .keys()
+        .chain(cache.symbol_ifunc_map.keys())
+        .filter(|candidate| candidate.as_str() != name)
+        .filter(|candidate| pretty(candidate).ends_with(last))
+        .cloned()
+        .collect::<Vec<_>>();
+    similar.sort();
+    similar
+}
@@ -1,30 +1,93 @@
+mod api;
 mod builder;
+mod cancel;
+mod classify;
+mod config;
 mod context;
+mod doctor;
+mod events;
 mod fat;
+mod fingerprint;
+mod gc;
+mod hash_files;
+mod http;
+mod inspect;
+mod orchestrator;
 mod patch;
+mod process;
+mod protocol;
+mod proxy;
+mod queue;
+mod release;
+mod report;
+mod status;
 mod thin;
+mod tui;
+mod wasi;
+mod wbg_shim;
+mod workspace;
 mod ws_server;
 
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
 use std::{path::PathBuf, sync::atomic::AtomicU64};
 
+use api::ApiServer;
 use builder::BuildCommand;
 use clap::Parser;
+use config::{ProjectMetadata, RunConfig};
 use context::Context;
 use dioxus_devtools::DevserverMsg;
+use events::EventLog;
 use multiqueue::BroadcastSender;
+use orchestrator::Orchestrator;
+use proxy::{ProxyServer, SiteState};
 use serde::{Deserialize, Serialize};
+use status::DevState;
 use target_lexicon::Triple;
 use tempfile::NamedTempFile;
 use ws_server::HotPatchServer;
 
+const HOT_PATCH_ADDR: &str = "127.0.0.1:3100";
+const DEFAULT_SITE_ADDR: &str = "127.0.0.1:3000";
+
 #[derive(clap::Parser)]
 struct Args {
     #[clap(long)]
     manifest_path: PathBuf,
+    /// Address of the JSON control API
+    #[clap(long, default_value = "127.0.0.1:3101")]
+    api_addr: String,
+    /// Show builders, clients and logs in a terminal UI
+    #[clap(long)]
+    tui: bool,
+    /// Append a JSON line describing every finished build to this file
+    #[clap(long)]
+    event_log: Option<PathBuf>,
+    /// Write build phases in the Chrome trace event format to this file
+    #[clap(long)]
+    chrome_trace: Option<PathBuf>,
+    /// How many fat dependency archives to keep around, including the one in use
+    #[clap(long, default_value_t = gc::DEFAULT_KEEP_FAT_ARCHIVES)]
+    keep_fat_archives: usize,
+    /// Keep objects, stubs, linker command lines and patches of thin builds in target/hotpatch-debug
+    #[clap(long)]
+    keep_temps: bool,
+    /// Start a fat rebuild when a patch references symbols the fat build doesn't have or new
+    /// wasm-bindgen imports no shim can be generated for
+    #[clap(long)]
+    fat_rebuild_on_unresolved: bool,
+    /// URL prefix wasm patches are loaded from, e.g. `/app/pkg/` or `http://127.0.0.1:8080/pkg/`.
+    /// Defaults to the `base-path` and `site-pkg-dir` of the project
+    #[clap(long)]
+    public_url: Option<String>,
+    /// Give the client files of fat builds content-hashed names and write `hash.txt` and
+    /// `manifest.json`, like `hash-files = true` in `[package.metadata.leptos]`
+    #[clap(long)]
+    hash_files: bool,
     #[clap(subcommand)]
     command: Command,
 }
@@ -33,6 +96,88 @@ struct Args {
 enum Command {
     Raw(RawArgs),
     Leptos(LeptosArgs),
+    /// Check the toolchain and the project without building anything
+    Doctor(DoctorArgs),
+    /// Remove patch libraries, fat archives and other hot-patch artifacts
+    Clean(CleanArgs),
+    /// Bundle builds kept with --keep-temps and logs into an archive for a bug report
+    Report(ReportArgs),
+    /// Look at the symbols of a fat binary and the jump tables of patches
+    Inspect(InspectArgs),
+    /// Build a deployable site without hot-patching
+    Build(BuildArgs),
+}
+
+#[derive(clap::Parser)]
+struct BuildArgs {
+    /// Use the release profile instead of dev
+    #[clap(long)]
+    release: bool,
+    /// Run wasm-opt on the client with this optimization level, e.g. `z` or `3`
+    #[clap(long)]
+    wasm_opt: Option<String>,
+    /// Keep the plain file names instead of content-hashed ones
+    #[clap(long)]
+    no_hash: bool,
+    #[clap(flatten)]
+    leptos: LeptosArgs,
+}
+
+#[derive(clap::Parser)]
+struct InspectArgs {
+    /// Fat binary, or the bindgened wasm module for the web
+    #[clap(long)]
+    fat: PathBuf,
+    /// Target of the fat binary, defaults to wasm32 for `.wasm` files and the host otherwise
+    #[clap(long)]
+    target: Option<Triple>,
+    #[clap(subcommand)]
+    command: InspectCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum InspectCommand {
+    /// Search symbols by mangled or demangled name
+    Symbols {
+        query: String,
+        #[clap(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Print the jump table of a patch
+    Patch { patch: PathBuf },
+    /// Explain why a symbol, or every undefined symbol of a patch, can't be resolved
+    Explain {
+        symbol: Option<String>,
+        #[clap(long)]
+        patch: Option<PathBuf>,
+    },
+}
+
+#[derive(clap::Parser)]
+struct ReportArgs {
+    /// Kept build directory to include, defaults to the latest build of every binary
+    #[clap(long)]
+    build: Vec<PathBuf>,
+    /// Extra file to include, e.g. a saved terminal log or the --event-log file
+    #[clap(long)]
+    include: Vec<PathBuf>,
+    /// Where to write the archive, defaults to target/hotpatch-report-<millis>.tar.gz
+    #[clap(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Parser)]
+struct CleanArgs {
+    /// Package whose `[package.metadata.leptos]` tells where the site pkg dir is
+    #[clap(long)]
+    package: Option<String>,
+}
+
+#[derive(clap::Parser)]
+struct DoctorArgs {
+    /// Also check that this package exists
+    #[clap(long)]
+    package: Option<String>,
 }
 
 #[derive(clap::Parser)]
@@ -57,6 +202,16 @@ struct LeptosArgs {
     lib_no_default_features: bool,
     #[clap(long)]
     lib_features: Vec<String>,
+
+    /// Address the server binary listens on, defaults to `site-addr` from `[package.metadata.leptos]`
+    #[clap(long)]
+    site_addr: Option<String>,
+    /// Address of the proxy that injects the hot-patch client, open this one in the browser
+    #[clap(long, default_value = "127.0.0.1:3001")]
+    proxy_addr: String,
+
+    #[clap(flatten)]
+    run: RunArgs,
 }
 
 #[derive(clap::Parser)]
@@ -77,6 +232,51 @@ struct RawArgs {
     rust_flags: Vec<String>,
     #[clap(long, default_value = "false")]
     no_default_features: bool,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+/// How to launch the binary after a fat build, extends `[package.metadata.leptos-subsecond.run]`
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Argument passed to the launched binary, can be repeated
+    #[clap(long = "run-arg", allow_hyphen_values = true)]
+    run_args: Vec<String>,
+    /// Environment variable in KEY=VALUE form, can be repeated
+    #[clap(long = "env")]
+    envs: Vec<String>,
+    /// Dotenv file with environment variables for the launched binary
+    #[clap(long)]
+    env_file: Option<PathBuf>,
+    /// Working directory of the launched binary, defaults to the project directory
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+    /// Restart the binary if it exits on its own
+    #[clap(long)]
+    auto_restart: bool,
+    /// How long to wait after SIGTERM before killing the binary
+    #[clap(long, default_value = "3000")]
+    stop_grace_period_ms: u64,
+    /// wasmtime or wasmer executable running WASI modules
+    #[clap(long)]
+    wasi_runtime: Option<PathBuf>,
+}
+
+impl RunArgs {
+    fn run_config(&self, metadata: &ProjectMetadata) -> RunConfig {
+        let mut run = metadata.tool.run.clone();
+        run.merge_cli(
+            &self.run_args,
+            &self.envs,
+            self.env_file.as_ref(),
+            self.cwd.as_ref(),
+        );
+        if let Some(wasi_runtime) = &self.wasi_runtime {
+            run.wasi_runtime = Some(wasi_runtime.clone());
+        }
+        run
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -96,9 +296,127 @@ pub enum LinkerFlavor {
 }
 
 fn main() {
-    tracing_subscriber::fmt::init();
+    // cargo runs this binary as rustc wrapper during fat builds
+    if let Some(code) = workspace::run_as_rustc_wrapper() {
+        std::process::exit(code);
+    }
+
+    let args = Args::parse();
+    let dev_state = Arc::new(DevState::default());
+
+    if args.tui {
+        dev_state.set_capture_output(true);
+        let log_state = Arc::clone(&dev_state);
+        tracing_subscriber::fmt()
+            .with_writer(move || tui::TuiLogWriter(Arc::clone(&log_state)))
+            .with_ansi(false)
+            .init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+
+    // cargo and rustc run in their own process groups and don't see the terminal's Ctrl+C
+    if let Err(err) = ctrlc::set_handler(|| {
+        cancel::kill_running();
+        std::process::exit(130);
+    }) {
+        tracing::warn!("Couldn't install the Ctrl+C handler: {err}");
+    }
+
+    if let Command::Doctor(doctor_args) = &args.command {
+        let healthy = doctor::run(&args.manifest_path, doctor_args.package.as_deref());
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+
+    if let Command::Inspect(inspect_args) = &args.command {
+        let triple = inspect_args
+            .target
+            .clone()
+            .unwrap_or_else(|| inspect::default_triple(&inspect_args.fat));
+        let res = match &inspect_args.command {
+            InspectCommand::Symbols { query, limit } => {
+                inspect::symbols(&inspect_args.fat, &triple, query, *limit)
+            }
+            InspectCommand::Patch { patch } => inspect::patch(&inspect_args.fat, patch, &triple),
+            InspectCommand::Explain { symbol, patch } => inspect::explain(
+                &inspect_args.fat,
+                patch.as_ref(),
+                symbol.as_deref(),
+                &triple,
+            ),
+        };
+        if let Err(err) = res {
+            eprintln!("{err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Command::Report(report_args) = &args.command {
+        let target_dir = args
+            .manifest_path
+            .canonicalize()
+            .unwrap()
+            .with_file_name("target");
+        match report::create_report(
+            &target_dir,
+            &report_args.build,
+            &report_args.include,
+            report_args.output.clone(),
+        ) {
+            Ok(path) => println!("Report written to {}", path.display()),
+            Err(err) => {
+                eprintln!("Couldn't create report: {err:#}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let mut args = Args::parse();
+    if let Command::Build(build_args) = &args.command {
+        let build = &build_args.leptos;
+        let mut metadata = config::load_project_metadata(&args.manifest_path, &build.package);
+        metadata.resolve_public_path(args.public_url.as_deref());
+        let server = backend_context(
+            &args.manifest_path,
+            build,
+            &metadata,
+            gc::DEFAULT_KEEP_FAT_ARCHIVES,
+            false,
+            false,
+        );
+        let client = frontend_context(
+            &args.manifest_path,
+            build,
+            &metadata,
+            gc::DEFAULT_KEEP_FAT_ARCHIVES,
+            false,
+            false,
+        );
+        let options = release::ReleaseOptions {
+            release: build_args.release,
+            wasm_opt: build_args.wasm_opt.clone(),
+            hash_files: !build_args.no_hash,
+            assets_dir: metadata.leptos.assets_dir.clone(),
+        };
+        if let Err(err) = release::build_site(server, client, &options) {
+            eprintln!("Build failed: {err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Command::Clean(clean_args) = &args.command {
+        clean(&args.manifest_path, clean_args.package.as_deref());
+        return;
+    }
+
+    if args.event_log.is_some() || args.chrome_trace.is_some() {
+        match EventLog::open(args.event_log.as_deref(), args.chrome_trace.as_deref()) {
+            Ok(event_log) => dev_state.set_event_log(event_log),
+            Err(err) => tracing::error!("Couldn't open build event log: {err}"),
+        }
+    }
 
     let manifest = args.manifest_path.canonicalize().unwrap();
     let mut working_dir = manifest.clone();
@@ -108,49 +426,103 @@ fn main() {
     std::fs::create_dir_all(&bundle_path).unwrap();
 
     let aslr_reference = Arc::new(AtomicU64::new(0));
+    let site_state = Arc::new(SiteState::default());
 
-    let (clear_patches_tx, tx) = spawn_hotpatch_server(Arc::clone(&aslr_reference));
-    let (back_command_tx, back_command_rx) = channel();
-    let (front_command_tx, front_command_rx) = channel();
+    let (clear_patches_tx, tx) =
+        spawn_hotpatch_server(Arc::clone(&aslr_reference), Arc::clone(&dev_state));
+    let (command_tx, command_rx) = channel();
 
-    let mut has_front = false;
+    let mut api_server = ApiServer::new(
+        &args.api_addr,
+        command_tx.clone(),
+        clear_patches_tx.clone(),
+        Arc::clone(&dev_state),
+    );
+    std::thread::spawn(move || api_server.run());
 
     match args.command {
         Command::Raw(mut raw_args) => {
             if raw_args.target == Triple::unknown() {
                 raw_args.target = Triple::host();
             }
+            let mut metadata = config::load_project_metadata(&manifest, &raw_args.package);
+            metadata.resolve_public_path(args.public_url.as_deref());
+            metadata.leptos.hash_files |= args.hash_files;
             spawn_raw_builder(
                 &args.manifest_path,
                 &raw_args,
+                &metadata,
                 tx,
+                clear_patches_tx.clone(),
                 aslr_reference,
-                back_command_rx,
+                command_rx,
+                site_state,
+                &dev_state,
+                args.keep_fat_archives,
+                args.keep_temps,
+                args.fat_rebuild_on_unresolved,
             );
         }
         Command::Leptos(mut leptos_args) => {
-            has_front = true;
-            spawn_backend_builder(
+            let mut metadata = config::load_project_metadata(&manifest, &leptos_args.package);
+            metadata.resolve_public_path(args.public_url.as_deref());
+            metadata.leptos.hash_files |= args.hash_files;
+            if leptos_args.site_addr.is_none() {
+                leptos_args.site_addr = Some(
+                    metadata
+                        .leptos
+                        .site_addr
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_SITE_ADDR.to_string()),
+                );
+            }
+            spawn_proxy_server(&leptos_args, Arc::clone(&site_state));
+            let backend = create_backend_builder(
                 &args.manifest_path,
                 &mut leptos_args,
+                &metadata,
                 tx.clone(),
+                clear_patches_tx.clone(),
                 Arc::clone(&aslr_reference),
-                back_command_rx,
+                Arc::clone(&site_state),
+                &dev_state,
+                args.keep_fat_archives,
+                args.keep_temps,
+                args.fat_rebuild_on_unresolved,
             );
 
-            spawn_frontend_builder(
+            let frontend = create_frontend_builder(
                 &args.manifest_path,
                 &mut leptos_args,
+                &metadata,
                 tx,
+                clear_patches_tx.clone(),
                 aslr_reference,
-                front_command_rx,
+                site_state,
+                &dev_state,
+                args.keep_fat_archives,
+                args.keep_temps,
+                args.fat_rebuild_on_unresolved,
             );
+
+            let mut orchestrator = Orchestrator::new(backend, frontend);
+            std::thread::spawn(move || orchestrator.run(command_rx));
         }
+        Command::Doctor(_)
+        | Command::Clean(_)
+        | Command::Report(_)
+        | Command::Inspect(_)
+        | Command::Build(_) => unreachable!("handled above"),
     }
 
-    back_command_tx.send(builder::BuildCommand::Fat).unwrap();
-    if has_front {
-        front_command_tx.send(BuildCommand::Fat).unwrap();
+    command_tx.send(BuildCommand::Fat).unwrap();
+
+    if args.tui {
+        if let Err(err) = tui::run(command_tx, clear_patches_tx, dev_state) {
+            eprintln!("Terminal UI failed: {err}");
+        }
+        cancel::kill_running();
+        return;
     }
 
     let mut line = String::new();
@@ -159,19 +531,14 @@ fn main() {
         std::io::stdin().read_line(&mut line).unwrap();
         match line.as_str().trim() {
             "r" => {
-                back_command_tx.send(builder::BuildCommand::Thin).unwrap();
-                if has_front {
-                    front_command_tx.send(BuildCommand::Thin).unwrap();
-                }
+                command_tx.send(BuildCommand::Thin).unwrap();
             }
             "R" => {
                 clear_patches_tx.send(()).unwrap();
-                back_command_tx
-                    .send(builder::BuildCommand::FatRebuild)
-                    .unwrap();
-                if has_front {
-                    front_command_tx.send(BuildCommand::FatRebuild).unwrap();
-                }
+                command_tx.send(BuildCommand::FatRebuild).unwrap();
+            }
+            "b" => {
+                command_tx.send(BuildCommand::Rollback).unwrap();
             }
             "e" => {
                 println!("EXITING");
@@ -180,10 +547,34 @@ fn main() {
             _ => (),
         }
     }
+    cancel::kill_running();
+}
+
+fn clean(manifest_path: &Path, package: Option<&str>) {
+    let manifest = manifest_path.canonicalize().unwrap();
+    let working_dir = manifest.parent().unwrap();
+    let metadata = package
+        .map(|package| config::load_project_metadata(&manifest, package))
+        .unwrap_or_default();
+    // patches are written to the bindgen out dir if it's configured
+    let site_pkg_dir = match metadata.tool.bindgen.out_dir {
+        Some(out_dir) => working_dir.join(out_dir),
+        None => working_dir
+            .join(metadata.leptos.site_root())
+            .join(metadata.leptos.site_pkg_dir()),
+    };
+    // patches are named after the binary, or the package for libraries
+    let (_, packages) = workspace::workspace_packages_in(working_dir);
+    let binary_names = packages
+        .into_iter()
+        .flat_map(|package| std::iter::once(package.name).chain(package.bins))
+        .collect::<Vec<_>>();
+    gc::clean(&working_dir.join("target"), &site_pkg_dir, &binary_names);
 }
 
 fn spawn_hotpatch_server(
     aslr_reference: Arc<AtomicU64>,
+    dev_state: Arc<DevState>,
 ) -> (Sender<()>, BroadcastSender<DevserverMsg>) {
     let (clear_patches_tx, clear_patches_rx) = channel::<()>();
     // TODO: reduce capacity of a queue
@@ -192,21 +583,40 @@ fn spawn_hotpatch_server(
     // so idk how to do it right now, I wrote websocket code very poorly
     let (tx, rx) = multiqueue::broadcast_queue(100);
     let mut hp_server = HotPatchServer::new(
-        "127.0.0.1:3100",
+        HOT_PATCH_ADDR,
         rx,
         Arc::clone(&aslr_reference),
         clear_patches_rx,
+        dev_state,
     );
     std::thread::spawn(move || hp_server.run());
     (clear_patches_tx, tx)
 }
 
+fn spawn_proxy_server(args: &LeptosArgs, site_state: Arc<SiteState>) {
+    let mut proxy = ProxyServer::new(
+        &args.proxy_addr,
+        args.site_addr.as_ref().unwrap(),
+        HOT_PATCH_ADDR,
+        site_state,
+    );
+    std::thread::spawn(move || proxy.run());
+}
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_raw_builder(
     manifest_path: &Path,
     args: &RawArgs,
+    metadata: &ProjectMetadata,
     patch_sender: BroadcastSender<DevserverMsg>,
+    clear_patches_tx: Sender<()>,
     aslr_reference: Arc<AtomicU64>,
     command_rx: Receiver<BuildCommand>,
+    site_state: Arc<SiteState>,
+    dev_state: &Arc<DevState>,
+    keep_fat_archives: usize,
+    keep_temps: bool,
+    fat_rebuild_on_unresolved: bool,
 ) {
     let manifest = manifest_path.canonicalize().unwrap();
     let mut working_dir = manifest.clone();
@@ -235,22 +645,76 @@ fn spawn_raw_builder(
         package: args.package.clone(),
         rust_flags: args.rust_flags.clone(),
         no_default_features: args.no_default_features,
-        site_dir: "target/site".to_string(),
-        site_pkg_dir: "pkg".to_string(),
+        site_dir: metadata.leptos.site_root(),
+        site_pkg_dir: metadata.leptos.site_pkg_dir(),
         wasm_bindgen_dir: "wasm-bindgen".to_string(),
+        site_addr: None,
+        reload_port: metadata.leptos.reload_port,
+        hash_files: metadata.leptos.hash_files,
+        run: args.run.run_config(metadata),
+        auto_restart: args.run.auto_restart,
+        stop_grace_period: Duration::from_millis(args.run.stop_grace_period_ms),
+        keep_fat_archives,
+        keep_temps,
+        fat_rebuild_on_unresolved,
+        bindgen: metadata.tool.bindgen.clone(),
     };
 
-    let mut builder = builder::Builder::new(ctx, patch_sender, aslr_reference, command_rx);
-    std::thread::spawn(move || builder.run());
+    let mut builder = builder::Builder::new(
+        ctx,
+        patch_sender,
+        clear_patches_tx,
+        aslr_reference,
+        site_state,
+        dev_state,
+    );
+    std::thread::spawn(move || builder.run(command_rx));
 }
 
-fn spawn_backend_builder(
+#[allow(clippy::too_many_arguments)]
+fn create_backend_builder(
     manifest_path: &Path,
     args: &mut LeptosArgs,
+    metadata: &ProjectMetadata,
     patch_sender: BroadcastSender<DevserverMsg>,
+    clear_patches_tx: Sender<()>,
     aslr_reference: Arc<AtomicU64>,
-    command_rx: Receiver<BuildCommand>,
-) {
+    site_state: Arc<SiteState>,
+    dev_state: &Arc<DevState>,
+    keep_fat_archives: usize,
+    keep_temps: bool,
+    fat_rebuild_on_unresolved: bool,
+) -> builder::Builder {
+    args.server_rust_flags
+        .push("cfg erase_components".to_string());
+
+    let ctx = backend_context(
+        manifest_path,
+        args,
+        metadata,
+        keep_fat_archives,
+        keep_temps,
+        fat_rebuild_on_unresolved,
+    );
+
+    builder::Builder::new(
+        ctx,
+        patch_sender,
+        clear_patches_tx,
+        aslr_reference,
+        site_state,
+        dev_state,
+    )
+}
+
+fn backend_context(
+    manifest_path: &Path,
+    args: &LeptosArgs,
+    metadata: &ProjectMetadata,
+    keep_fat_archives: usize,
+    keep_temps: bool,
+    fat_rebuild_on_unresolved: bool,
+) -> Context {
     let manifest = manifest_path.canonicalize().unwrap();
     let mut working_dir = manifest.clone();
     working_dir.pop();
@@ -262,10 +726,7 @@ fn spawn_backend_builder(
     let link_args_file = NamedTempFile::with_suffix(".txt").unwrap();
     let link_err_file = NamedTempFile::with_suffix(".txt").unwrap();
 
-    args.server_rust_flags
-        .push("cfg erase_components".to_string());
-
-    let ctx = Context {
+    Context {
         target_dir,
         working_dir,
         bin: Some(args.server_bin.clone()),
@@ -281,22 +742,66 @@ fn spawn_backend_builder(
         package: args.package.clone(),
         rust_flags: args.server_rust_flags.clone(),
         no_default_features: args.server_no_default_features,
-        site_dir: "target/site".to_string(),
-        site_pkg_dir: "pkg".to_string(),
+        site_dir: metadata.leptos.site_root(),
+        site_pkg_dir: metadata.leptos.site_pkg_dir(),
         wasm_bindgen_dir: "wasm-bindgen".to_string(),
-    };
-
-    let mut builder = builder::Builder::new(ctx, patch_sender, aslr_reference, command_rx);
-    std::thread::spawn(move || builder.run());
+        site_addr: args.site_addr.clone(),
+        reload_port: metadata.leptos.reload_port,
+        hash_files: metadata.leptos.hash_files,
+        run: args.run.run_config(metadata),
+        auto_restart: args.run.auto_restart,
+        stop_grace_period: Duration::from_millis(args.run.stop_grace_period_ms),
+        keep_fat_archives,
+        keep_temps,
+        fat_rebuild_on_unresolved,
+        // the server needs the client's out name and dir to serve its files
+        bindgen: metadata.tool.bindgen.clone(),
+    }
 }
 
-fn spawn_frontend_builder(
+#[allow(clippy::too_many_arguments)]
+fn create_frontend_builder(
     manifest_path: &Path,
     args: &mut LeptosArgs,
+    metadata: &ProjectMetadata,
     patch_sender: BroadcastSender<DevserverMsg>,
+    clear_patches_tx: Sender<()>,
     aslr_reference: Arc<AtomicU64>,
-    command_rx: Receiver<BuildCommand>,
-) {
+    site_state: Arc<SiteState>,
+    dev_state: &Arc<DevState>,
+    keep_fat_archives: usize,
+    keep_temps: bool,
+    fat_rebuild_on_unresolved: bool,
+) -> builder::Builder {
+    args.lib_rust_flags.push("cfg erase_components".to_string());
+
+    let ctx = frontend_context(
+        manifest_path,
+        args,
+        metadata,
+        keep_fat_archives,
+        keep_temps,
+        fat_rebuild_on_unresolved,
+    );
+
+    builder::Builder::new(
+        ctx,
+        patch_sender,
+        clear_patches_tx,
+        aslr_reference,
+        site_state,
+        dev_state,
+    )
+}
+
+fn frontend_context(
+    manifest_path: &Path,
+    args: &LeptosArgs,
+    metadata: &ProjectMetadata,
+    keep_fat_archives: usize,
+    keep_temps: bool,
+    fat_rebuild_on_unresolved: bool,
+) -> Context {
     let manifest = manifest_path.canonicalize().unwrap();
     let mut working_dir = manifest.clone();
     working_dir.pop();
@@ -308,11 +813,10 @@ fn spawn_frontend_builder(
     let link_args_file = NamedTempFile::with_suffix(".txt").unwrap();
     let link_err_file = NamedTempFile::with_suffix(".txt").unwrap();
 
-    args.lib_rust_flags
-        .push("cfg getrandom_backend=\"wasm_js\"".to_string());
-    args.lib_rust_flags.push("cfg erase_components".to_string());
+    let mut rust_flags = args.lib_rust_flags.clone();
+    rust_flags.push("cfg getrandom_backend=\"wasm_js\"".to_string());
 
-    let ctx = Context {
+    Context {
         target_dir,
         working_dir,
         bin: None,
@@ -326,13 +830,20 @@ fn spawn_frontend_builder(
         profile_name: "dev".to_string(),
         profile_dir: "debug".to_string(),
         package: args.package.clone(),
-        rust_flags: args.lib_rust_flags.clone(),
+        rust_flags,
         no_default_features: args.lib_no_default_features,
-        site_dir: "target/site".to_string(),
-        site_pkg_dir: "pkg".to_string(),
+        site_dir: metadata.leptos.site_root(),
+        site_pkg_dir: metadata.leptos.site_pkg_dir(),
         wasm_bindgen_dir: "wasm-bindgen".to_string(),
-    };
-
-    let mut builder = builder::Builder::new(ctx, patch_sender, aslr_reference, command_rx);
-    std::thread::spawn(move || builder.run());
+        site_addr: None,
+        reload_port: metadata.leptos.reload_port,
+        hash_files: metadata.leptos.hash_files,
+        run: RunConfig::default(),
+        auto_restart: false,
+        stop_grace_period: Duration::from_millis(args.run.stop_grace_period_ms),
+        keep_fat_archives,
+        keep_temps,
+        fat_rebuild_on_unresolved,
+        bindgen: metadata.tool.bindgen.clone(),
+    }
 }
@@ -0,0 +1,176 @@
+// Drives the server and client builders of a leptos project together.
+//
+// Patches for both sides are built concurrently, but they are published only when both of them
+// succeeded, otherwise the server would render html with different code than the one used to hydrate it.
+
+use std::sync::mpsc::Receiver;
+
+use dioxus_devtools::HotReloadMsg;
+
+use crate::{
+    builder::{BuildCommand, Builder},
+    cancel::CancelToken,
+    queue::BuildQueue,
+};
+
+pub struct Orchestrator {
+    backend: Builder,
+    frontend: Builder,
+    /// Which sides got a patch in every thin build since the last fat build, `(backend, frontend)`
+    sent: Vec<(bool, bool)>,
+}
+
+impl Orchestrator {
+    pub fn new(backend: Builder, frontend: Builder) -> Self {
+        Self {
+            backend,
+            frontend,
+            sent: vec![],
+        }
+    }
+
+    pub fn run(&mut self, command_receiver: Receiver<BuildCommand>) {
+        let queue = BuildQueue::spawn(command_receiver);
+        while let Some((command, cancel)) = queue.next() {
+            match command {
+                BuildCommand::Rollback => self.rollback(),
+                BuildCommand::Thin => self.build_thin(&cancel),
+                BuildCommand::Fat => {
+                    self.sent.clear();
+                    self.both(Builder::build_fat, &cancel);
+                }
+                BuildCommand::FatRebuild => {
+                    self.sent.clear();
+                    self.both(Builder::rebuild_fat, &cancel);
+                }
+            }
+            queue.finish();
+        }
+    }
+
+    fn both(&mut self, f: fn(&mut Builder, &CancelToken), cancel: &CancelToken) {
+        let Self {
+            backend, frontend, ..
+        } = self;
+        std::thread::scope(|s| {
+            s.spawn(move || f(backend, cancel));
+            s.spawn(move || f(frontend, cancel));
+        });
+    }
+
+    pub fn build_thin(&mut self, cancel: &CancelToken) {
+        // server and client are built from the same manifest, both go fat together
+        if let Some(reason) = self
+            .backend
+            .fat_reason()
+            .or_else(|| self.frontend.fat_reason())
+        {
+            tracing::info!("Starting a fat rebuild, {reason}");
+            // both builders share the hot-patch server
+            self.backend.clear_patches();
+            self.sent.clear();
+            self.both(Builder::rebuild_fat, cancel);
+            return;
+        }
+
+        // mutable borrows only need `Builder: Send` to cross threads
+        let Self {
+            backend, frontend, ..
+        } = self;
+        let (backend_patch, frontend_patch) = std::thread::scope(|s| {
+            let backend_patch = s.spawn(move || backend.prepare_thin(cancel));
+            let frontend_patch = s.spawn(move || frontend.prepare_thin(cancel));
+            (
+                backend_patch.join().unwrap(),
+                frontend_patch.join().unwrap(),
+            )
+        });
+
+        if cancel.is_cancelled() {
+            tracing::info!("Thin build cancelled");
+            self.discard(&backend_patch, &frontend_patch);
+            return;
+        }
+
+        match (backend_patch, frontend_patch) {
+            (Ok(None), Ok(None)) => {
+                tracing::info!("No code changes, nothing to patch");
+            }
+            (Ok(backend_patch), Ok(frontend_patch)) => {
+                // only the side whose code changed has a patch
+                self.sent
+                    .push((backend_patch.is_some(), frontend_patch.is_some()));
+                if let Some(patch) = backend_patch {
+                    self.backend.send_patch(patch);
+                }
+                if let Some(patch) = frontend_patch {
+                    self.frontend.send_patch(patch);
+                }
+            }
+            (Err(err), Ok(frontend_patch)) => {
+                self.discard(&Ok(None), &Ok(frontend_patch));
+                tracing::error!("Server patch failed, nothing was sent: {err:#}");
+                self.rebuild_fat_if_needed(&[&err], cancel);
+            }
+            (Ok(backend_patch), Err(err)) => {
+                self.discard(&Ok(backend_patch), &Ok(None));
+                tracing::error!("Client patch failed, nothing was sent: {err:#}");
+                self.rebuild_fat_if_needed(&[&err], cancel);
+            }
+            (Err(backend_err), Err(frontend_err)) => {
+                tracing::error!(
+                    "Both patches failed, nothing was sent\nserver: {backend_err:#}\nclient: {frontend_err:#}"
+                );
+                self.rebuild_fat_if_needed(&[&backend_err, &frontend_err], cancel);
+            }
+        }
+    }
+
+    /// Rebuilds both sides when a patch needs symbols the fat builds don't have, if configured
+    fn rebuild_fat_if_needed(&mut self, errors: &[&anyhow::Error], cancel: &CancelToken) {
+        // both builders share the setting
+        if errors.iter().any(|err| self.backend.rebuilds_fat_on(err)) {
+            tracing::info!("Starting a fat rebuild to resolve the missing symbols");
+            self.backend.clear_patches();
+            self.sent.clear();
+            self.both(Builder::rebuild_fat, cancel);
+        }
+    }
+
+    /// Marks prepared patches that won't be sent as done
+    fn discard(
+        &mut self,
+        backend_patch: &anyhow::Result<Option<HotReloadMsg>>,
+        frontend_patch: &anyhow::Result<Option<HotReloadMsg>>,
+    ) {
+        if let Ok(Some(patch)) = backend_patch {
+            self.backend.discard_patch(patch);
+        }
+        if let Ok(Some(patch)) = frontend_patch {
+            self.frontend.discard_patch(patch);
+        }
+    }
+
+    /// Undoes the last thin build on both sides, so server and client code stay in sync
+    fn rollback(&mut self) {
+        let Some(&(backend, frontend)) = self.sent.last() else {
+            tracing::warn!("Nothing to roll back");
+            return;
+        };
+        // rolling back only one side would leave server and client out of sync
+        if (backend && !self.backend.can_rollback()) || (frontend && !self.frontend.can_rollback())
+        {
+            tracing::error!(
+                "Can't roll back the last patch, do a fat rebuild to undo the first patch"
+            );
+            return;
+        }
+        self.sent.pop();
+        if backend {
+            self.backend.rollback();
+        }
+        if frontend {
+            self.frontend.rollback();
+        }
+    }
+}
@@ -27,6 +27,10 @@ use wasmparser::{
 
 type Result<T, E = PatchError> = std::result::Result<T, E>;
 
+/// Import module of wasm-bindgen imports that are new in a patch, satisfied by the JS shim shipped
+/// alongside the patch (see `wbg_shim`)
+pub const WBG_SHIM_MODULE: &str = "__subsecond_wbg_shim__";
+
 #[derive(Debug, Error)]
 pub enum PatchError {
     #[error("Failed to read file: {0}")]
@@ -57,6 +61,82 @@ pub enum PatchError {
 
     #[error("Unsupported platform: {0}")]
     UnsupportedPlatform(String),
+
+    #[error("{0}")]
+    UnresolvedImports(UnresolvedImports),
+
+    /// New wasm-bindgen imports of the patch that no JS shim can be generated for
+    #[error("{0}. Do a fat rebuild")]
+    UnshimmableImports(String),
+}
+
+impl PatchError {
+    /// Whether the patch can only work against a new fat build
+    pub fn needs_fat_rebuild(&self) -> bool {
+        matches!(
+            self,
+            PatchError::UnresolvedImports(_) | PatchError::UnshimmableImports(_)
+        )
+    }
+}
+
+/// Why an import of a wasm patch can't be satisfied by the base module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnresolvedCause {
+    /// The function isn't in the base module at all, it was never used or eliminated there
+    EliminatedInBase,
+    /// The data symbol isn't in the symbol table of the base module
+    MissingDataSymbol,
+    /// The base module has the same function under another hash, the monomorphization or the
+    /// function merging changed
+    MergedByMonomorphization,
+}
+
+impl std::fmt::Display for UnresolvedCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            UnresolvedCause::EliminatedInBase => "function missing from the fat build",
+            UnresolvedCause::MissingDataSymbol => "data symbol missing from the fat build",
+            UnresolvedCause::MergedByMonomorphization => {
+                "fat build has it under another instantiation"
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnresolvedImport {
+    pub module: String,
+    pub name: String,
+    pub cause: UnresolvedCause,
+}
+
+/// Every import of a wasm patch the base module can't satisfy
+#[derive(Debug, Clone)]
+pub struct UnresolvedImports(pub Vec<UnresolvedImport>);
+
+impl std::fmt::Display for UnresolvedImports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "The patch needs {} symbols the fat build doesn't have:",
+            self.0.len()
+        )?;
+        for import in &self.0 {
+            writeln!(
+                f,
+                "  {}.{:#} ({})\n      {}",
+                import.module,
+                rustc_demangle::demangle(&import.name),
+                import.cause,
+                import.name
+            )?;
+        }
+        write!(
+            f,
+            "The fat build has to be redone to include them (press R, or pass --fat-rebuild-on-unresolved to do it automatically)"
+        )
+    }
 }
 
 /// A cache for the hotpatching engine that stores the original module's parsed symbol table.
@@ -190,7 +270,7 @@ impl HotpatchModuleCache {
             //
             // We need to take an extra step to account for merged functions by mapping function index
             // to a set of functions that point to the same index.
-            _ if triple.architecture == Architecture::Wasm32 => {
+            _ if is_wasm(triple) => {
                 let bytes = std::fs::read(original)?;
                 let ParsedModule {
                     module, symbols, ..
@@ -304,7 +384,7 @@ pub fn create_jump_table(
     // - nix requires the object crate
     match triple.operating_system {
         OperatingSystem::Windows => create_windows_jump_table(patch, cache),
-        _ if triple.architecture == Architecture::Wasm32 => create_wasm_jump_table(patch, cache),
+        _ if is_wasm(triple) => create_wasm_jump_table(patch, cache),
         _ => create_native_jump_table(patch, triple, cache),
     }
 }
@@ -430,6 +510,7 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
     let mut got_funcs = vec![];
     let mut wbg_funcs = vec![];
     let mut env_funcs = vec![];
+    let mut unresolved = vec![];
 
     // Collect all the GOT entries from the new module.
     // The GOT imports come from the wasm-ld implementation of the dynamic linking spec
@@ -450,15 +531,14 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
     // name to match the prefixed export name in the base module.
     for import in new.imports.iter() {
         match import.module.as_str() {
-            "GOT.func" => {
-                let Some(entry) = name_to_ifunc_old.get(import.name.as_str()).cloned() else {
-                    return Err(PatchError::InvalidModule(format!(
-                        "Expected to find GOT.func entry in ifunc table: {}",
-                        import.name.as_str()
-                    )));
-                };
-                got_funcs.push((import.id(), entry));
-            }
+            "GOT.func" => match name_to_ifunc_old.get(import.name.as_str()).cloned() {
+                Some(entry) => got_funcs.push((import.id(), entry)),
+                None => unresolved.push(UnresolvedImport {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                    cause: missing_function_cause(cache, &import.name),
+                }),
+            },
             "GOT.mem" => got_mems.push(import.id()),
             "env" => env_funcs.push(import.id()),
             "__wbindgen_placeholder__" => wbg_funcs.push(import.id()),
@@ -502,8 +582,8 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
         // "satisfying" the import means removing it from the import table and replacing its target
         // value with a local global.
         new.imports.delete(import_id);
-        new.globals.get_mut(id).kind =
-            walrus::GlobalKind::Local(ConstExpr::Value(walrus::ir::Value::I32(ifunc_index)));
+        let global = new.globals.get_mut(id);
+        global.kind = walrus::GlobalKind::Local(address_value(global.ty, ifunc_index as i64));
     }
 
     // We need to satisfy the GOT.mem imports of this side module. The GOT.mem imports come from the wasm-ld
@@ -528,12 +608,18 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
     // its data segment as the value within the global.
     for mem in got_mems {
         let import = new.imports.get(mem);
-        let data_symbol_idx = *old_symbols
+        let Some(data_symbol_idx) = old_symbols
             .data_symbol_map
             .get(import.name.as_str())
-            .with_context(|| {
-                format!("Failed to find GOT.mem import by its name: {}", import.name)
-            })?;
+            .copied()
+        else {
+            unresolved.push(UnresolvedImport {
+                module: import.module.clone(),
+                name: import.name.clone(),
+                cause: UnresolvedCause::MissingDataSymbol,
+            });
+            continue;
+        };
         let data_symbol = old_symbols
             .data_symbols
             .get(&data_symbol_idx)
@@ -548,11 +634,12 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
             DataKind::Active {
                 offset: ConstExpr::Value(walrus::ir::Value::I32(idx)),
                 ..
-            } => idx,
+            } => idx as i64,
+            // memory64
             DataKind::Active {
                 offset: ConstExpr::Value(walrus::ir::Value::I64(idx)),
                 ..
-            } => idx as i32,
+            } => idx,
             _ => {
                 return Err(PatchError::InvalidModule(format!(
                     "Data segment of invalid table: {:?}",
@@ -570,8 +657,10 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
         // "satisfying" the import means removing it from the import table and replacing its target
         // value with a local global.
         new.imports.delete(mem);
-        new.globals.get_mut(global_id).kind = walrus::GlobalKind::Local(ConstExpr::Value(
-            walrus::ir::Value::I32(offset + data_symbol.segment_offset as i32),
+        let global = new.globals.get_mut(global_id);
+        global.kind = walrus::GlobalKind::Local(address_value(
+            global.ty,
+            offset + data_symbol.segment_offset as i64,
         ));
     }
 
@@ -610,24 +699,44 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
                 *table_idx,
                 name.clone(),
             );
-        }
-
-        if name_is_bindgen_symbol(&name) {
+        } else if name_is_bindgen_symbol(&name) {
             new.imports.delete(env_func_import);
             convert_import_to_ifunc_call(&mut new, ifunc_table_initializer, func_id, 0, name);
+        } else {
+            // the loader would fail to instantiate the patch with it
+            unresolved.push(UnresolvedImport {
+                module: "env".to_string(),
+                cause: missing_function_cause(cache, &name),
+                name,
+            });
         }
     }
 
+    // report every missing symbol at once instead of one per fat rebuild attempt
+    if !unresolved.is_empty() {
+        return Err(PatchError::UnresolvedImports(UnresolvedImports(unresolved)));
+    }
+
     // Wire up the preserved intrinsic functions that we saved before running wasm-bindgen to the expected
     // imports from the patch.
+    //
+    // Imports the base module never had (a new `web_sys` call, a new closure shape) have no saved
+    // export to point at. They are moved to a separate import module instead, the JS glue for them is
+    // generated from the unprocessed patch and shipped next to it.
     for import_id in wbg_funcs {
         let import = new.imports.get_mut(import_id);
         let ImportKind::Function(func_id) = import.kind else {
             continue;
         };
 
+        let saved = format!("__saved_wbg_{}", import.name);
+        if !cache.old_exports.contains(&saved) && !name_is_bindgen_symbol(&saved) {
+            import.module = WBG_SHIM_MODULE.into();
+            continue;
+        }
+
         import.module = "env".into();
-        import.name = format!("__saved_wbg_{}", import.name);
+        import.name = saved;
 
         if name_is_bindgen_symbol(&import.name) {
             let name = import.name.as_str().to_string();
@@ -639,10 +748,10 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
     // Wipe away the unnecessary sections
     let customs = new.customs.iter().map(|f| f.0).collect::<Vec<_>>();
     for custom_id in customs {
-        if let Some(custom) = new.customs.get_mut(custom_id) {
-            if custom.name().contains("manganis") || custom.name().contains("__wasm_bindgen") {
-                new.customs.delete(custom_id);
-            }
+        if let Some(custom) = new.customs.get_mut(custom_id)
+            && (custom.name().contains("manganis") || custom.name().contains("__wasm_bindgen"))
+        {
+            new.customs.delete(custom_id);
         }
     }
 
@@ -675,6 +784,31 @@ fn create_wasm_jump_table(patch: &Path, cache: &HotpatchModuleCache) -> Result<J
     })
 }
 
+/// Value of a GOT global, which is an `i64` for wasm64 and an `i32` otherwise
+fn address_value(ty: walrus::ValType, value: i64) -> ConstExpr {
+    match ty {
+        walrus::ValType::I64 => ConstExpr::Value(walrus::ir::Value::I64(value)),
+        _ => ConstExpr::Value(walrus::ir::Value::I32(value as i32)),
+    }
+}
+
+/// Tells a function that's gone from the base module apart from one whose instantiation got another
+/// symbol hash
+fn missing_function_cause(cache: &HotpatchModuleCache, name: &str) -> UnresolvedCause {
+    let pretty = |name: &str| format!("{:#}", rustc_demangle::demangle(name));
+    let wanted = pretty(name);
+    let same_path = cache
+        .symbol_ifunc_map
+        .keys()
+        .chain(cache.old_exports.iter())
+        .any(|candidate| candidate != name && pretty(candidate) == wanted);
+    if same_path {
+        UnresolvedCause::MergedByMonomorphization
+    } else {
+        UnresolvedCause::EliminatedInBase
+    }
+}
+
 fn convert_import_to_ifunc_call(
     new: &mut Module,
     ifunc_table_initializer: TableId,
@@ -702,10 +836,13 @@ fn convert_import_to_ifunc_call(
         body.local_get(*arg);
     }
 
-    // And then the address of the indirect function
-    body.instr(ir::Instr::Const(ir::Const {
-        value: ir::Value::I32(table_idx),
-    }));
+    // And then the address of the indirect function, tables of memory64 modules can be indexed by i64
+    let value = if new.tables.get(ifunc_table_initializer).table64 {
+        ir::Value::I64(table_idx as i64)
+    } else {
+        ir::Value::I32(table_idx)
+    };
+    body.instr(ir::Instr::Const(ir::Const { value }));
 
     // And call it
     body.instr(ir::Instr::CallIndirect(ir::CallIndirect {
@@ -807,6 +944,7 @@ pub fn create_undefined_symbol_stub(
         match triple.architecture {
             Architecture::Aarch64(_) => object::Architecture::Aarch64,
             Architecture::Wasm32 => object::Architecture::Wasm32,
+            Architecture::Wasm64 => object::Architecture::Wasm64,
             Architecture::X86_64 => object::Architecture::X86_64,
             _ => return Err(PatchError::UnsupportedPlatform(triple.to_string())),
         },
@@ -1162,6 +1300,10 @@ pub fn create_undefined_symbol_stub(
 /// to the export table.
 ///
 /// It also moves all functions and memories to be callable indirectly.
+///
+/// Memory64 modules are handled the same way: the ifunc table is grown by its `u64` limits, and the
+/// offsets of active element and data segments are emitted as `i64` for 64-bit tables and memories,
+/// like [`address_value`] does for the GOT of patches.
 pub fn prepare_wasm_base_module(bytes: &[u8]) -> Result<Vec<u8>> {
     let ParsedModule {
         mut module,
@@ -1272,10 +1414,10 @@ pub fn prepare_wasm_base_module(bytes: &[u8]) -> Result<Vec<u8>> {
         // around issues on large projects where we hit the maximum number of exports.
         //
         // https://github.com/emscripten-core/emscripten/issues/22863
-        if let FunctionKind::Local(_) = &func.kind {
-            if !ifuncs.contains(&func.id()) {
-                make_indirect.push(func.id());
-            }
+        if let FunctionKind::Local(_) = &func.kind
+            && !ifuncs.contains(&func.id())
+        {
+            make_indirect.push(func.id());
         }
     }
 
@@ -1303,11 +1445,53 @@ pub fn prepare_wasm_base_module(bytes: &[u8]) -> Result<Vec<u8>> {
         if let Some(max) = table.maximum {
             table.maximum = Some(max + make_indirect_count);
         }
+        if !table.table64 && table.initial > u32::MAX as u64 {
+            return Err(PatchError::InvalidModule(
+                "ifunc table outgrew its 32-bit limits".into(),
+            ));
+        }
     }
 
+    set_address_offsets(&mut module);
+
     Ok(module.emit_wasm())
 }
 
+/// Types the constant offsets of active segments by the index type of their table or memory
+fn set_address_offsets(module: &mut Module) {
+    let address_type = |is64: bool| {
+        if is64 {
+            walrus::ValType::I64
+        } else {
+            walrus::ValType::I32
+        }
+    };
+    let constant = |offset: &ConstExpr| match offset {
+        ConstExpr::Value(walrus::ir::Value::I32(value)) => Some(*value as u32 as i64),
+        ConstExpr::Value(walrus::ir::Value::I64(value)) => Some(*value),
+        _ => None,
+    };
+
+    for segment in module.elements.iter_mut() {
+        if let ElementKind::Active { table, offset } = &mut segment.kind
+            && let Some(value) = constant(offset)
+        {
+            let ty = address_type(module.tables.get(*table).table64);
+            *offset = address_value(ty, value);
+        }
+    }
+    let data_ids = module.data.iter().map(|data| data.id()).collect::<Vec<_>>();
+    for id in data_ids {
+        let segment = module.data.get_mut(id);
+        if let DataKind::Active { memory, offset } = &mut segment.kind
+            && let Some(value) = constant(offset)
+        {
+            let ty = address_type(module.memories.get(*memory).memory64);
+            *offset = address_value(ty, value);
+        }
+    }
+}
+
 /// Check if the name is a wasm-bindgen symbol
 ///
 /// todo(jon): I believe we can just look at all the functions the wasm_bindgen describe export references.
@@ -1316,7 +1500,7 @@ pub fn prepare_wasm_base_module(bytes: &[u8]) -> Result<Vec<u8>> {
 /// Uses the heuristics from the wasm-bindgen source code itself:
 ///
 /// <https://github.com/rustwasm/wasm-bindgen/blob/c35cc9369d5e0dc418986f7811a0dd702fb33ef9/crates/cli-support/src/wit/mod.rs#L1165>
-fn name_is_bindgen_symbol(name: &str) -> bool {
+pub fn name_is_bindgen_symbol(name: &str) -> bool {
     name.contains("__wbindgen_describe")
         || name.contains("__wbindgen_externref")
         || name.contains("wasm_bindgen8describe6inform")
@@ -1325,6 +1509,25 @@ fn name_is_bindgen_symbol(name: &str) -> bool {
         || name.contains("wasm_bindgen7closure16Closure$LT$T$GT$4wrap8describe")
 }
 
+/// Whether patches for `triple` are wasm side modules, for both wasm32 and wasm64 (memory64)
+pub fn is_wasm(triple: &Triple) -> bool {
+    matches!(
+        triple.architecture,
+        Architecture::Wasm32 | Architecture::Wasm64
+    )
+}
+
+/// Imports of a processed patch that have to be satisfied by its wasm-bindgen shim
+pub fn wbg_shim_imports(patch: &Path) -> Result<Vec<String>> {
+    let module = Module::from_file(patch)?;
+    Ok(module
+        .imports
+        .iter()
+        .filter(|import| import.module == WBG_SHIM_MODULE)
+        .map(|import| import.name.clone())
+        .collect())
+}
+
 /// Manually parse the data section from a wasm module
 ///
 /// We need to do this for data symbols because walrus doesn't provide the right range and offset
@@ -1417,6 +1620,15 @@ fn parse_bytes_to_data_segment(bytes: &[u8]) -> Result<RawDataSection<'_>> {
     })
 }
 
+/// Names of the data symbols of a wasm module, these are what GOT.mem imports resolve against
+pub fn wasm_data_symbols(bytes: &[u8]) -> Result<HashSet<String>> {
+    Ok(parse_bytes_to_data_segment(bytes)?
+        .data_symbol_map
+        .keys()
+        .map(|name| name.to_string())
+        .collect())
+}
+
 struct RawDataSection<'a> {
     _data_range: Range<usize>,
     symbols: Vec<SymbolInfo<'a>>,
@@ -1475,7 +1687,7 @@ fn parse_module_with_ids(bindgened: &[u8]) -> Result<ParsedModule<'_>> {
 ///
 /// We need to special case darwin since `main` is the entrypoint but `_main` is the actual symbol.
 /// The entrypoint ends up outside the text section, seemingly, and breaks our aslr detection.
-fn main_sentinel(triple: &Triple) -> &'static str {
+pub fn main_sentinel(triple: &Triple) -> &'static str {
     match triple.operating_system {
         // The symbol in the symtab is called "_main" but in the dysymtab it is called "main"
         OperatingSystem::MacOSX(_) | OperatingSystem::Darwin(_) | OperatingSystem::IOS(_) => {
@@ -0,0 +1,295 @@
+// Supervision of the native binary launched after a fat build.
+//
+// The child's stdout/stderr are piped through prefixed, colored log lines so the server output can be
+// told apart from the build output. A monitor thread reports exits and panics and optionally restarts
+// the binary with exponential backoff.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::status::DevState;
+
+const STDOUT_COLOR: &str = "\x1b[36m";
+const STDERR_COLOR: &str = "\x1b[33m";
+
+/// If the process ran at least this long before exiting, the backoff is reset.
+const STABLE_RUN_TIME: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    pub enabled: bool,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProcessSpec {
+    /// Name used to prefix output lines
+    pub name: String,
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+    pub cwd: PathBuf,
+    /// Receives the output lines of the process
+    pub output: Arc<DevState>,
+}
+
+pub struct ServerProcess {
+    child: Arc<Mutex<Child>>,
+    pid: Arc<AtomicU32>,
+    stopping: Arc<AtomicBool>,
+    monitor: Option<JoinHandle<()>>,
+    grace_period: Duration,
+}
+
+impl ServerProcess {
+    pub fn spawn(
+        spec: ProcessSpec,
+        restart_policy: RestartPolicy,
+        grace_period: Duration,
+    ) -> std::io::Result<Self> {
+        let panicked = Arc::new(AtomicBool::new(false));
+        let child = spawn_child(&spec, &panicked)?;
+        let pid = Arc::new(AtomicU32::new(child.id()));
+        let child = Arc::new(Mutex::new(child));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let monitor = {
+            let child = Arc::clone(&child);
+            let pid = Arc::clone(&pid);
+            let stopping = Arc::clone(&stopping);
+            std::thread::spawn(move || {
+                monitor_loop(spec, restart_policy, child, pid, stopping, panicked)
+            })
+        };
+
+        Ok(Self {
+            child,
+            pid,
+            stopping,
+            monitor: Some(monitor),
+            grace_period,
+        })
+    }
+
+    /// Pid of the currently running process, changes when the process is restarted
+    pub fn pid(&self) -> u32 {
+        self.pid.load(Ordering::SeqCst)
+    }
+
+    /// Asks the process to terminate and kills it if it's still alive after the grace period
+    pub fn stop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        {
+            let mut child = self.child.lock().unwrap();
+            if let Ok(None) = child.try_wait() {
+                terminate(&mut child);
+
+                let deadline = Instant::now() + self.grace_period;
+                while Instant::now() < deadline {
+                    if let Ok(Some(_)) = child.try_wait() {
+                        break;
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+
+                if let Ok(None) = child.try_wait() {
+                    tracing::warn!(
+                        "Server didn't stop in {}s, killing it",
+                        self.grace_period.as_secs_f32()
+                    );
+                    match child.kill() {
+                        Ok(_) => tracing::debug!("Killed executable successfully"),
+                        Err(_) => tracing::error!("Couldn't kill running executable!"),
+                    }
+                }
+                _ = child.wait();
+            }
+        }
+
+        if let Some(monitor) = self.monitor.take() {
+            _ = monitor.join();
+        }
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Blocks until nothing is listening on `addr` or the timeout runs out
+pub fn wait_for_port_free(addr: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if TcpListener::bind(addr).is_ok() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("Address {addr} is still in use");
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    let res = Command::new("kill")
+        .arg("-TERM")
+        .arg(child.id().to_string())
+        .status();
+    if !res.is_ok_and(|s| s.success()) {
+        _ = child.kill();
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    _ = child.kill();
+}
+
+fn spawn_child(spec: &ProcessSpec, panicked: &Arc<AtomicBool>) -> std::io::Result<Child> {
+    let mut cmd = Command::new(&spec.program);
+    cmd.args(&spec.args)
+        .envs(spec.envs.iter().map(|(k, v)| (k, v)))
+        .current_dir(&spec.cwd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+
+    panicked.store(false, Ordering::SeqCst);
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(
+            stdout,
+            spec.name.clone(),
+            STDOUT_COLOR,
+            Arc::clone(&spec.output),
+            Arc::clone(panicked),
+        );
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(
+            stderr,
+            spec.name.clone(),
+            STDERR_COLOR,
+            Arc::clone(&spec.output),
+            Arc::clone(panicked),
+        );
+    }
+
+    Ok(child)
+}
+
+fn forward_output(
+    output: impl Read + Send + 'static,
+    name: String,
+    color: &'static str,
+    dev_state: Arc<DevState>,
+    panicked: Arc<AtomicBool>,
+) {
+    std::thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.contains("panicked at") {
+                panicked.store(true, Ordering::SeqCst);
+            }
+            dev_state.server_output(&name, color, &line);
+        }
+    });
+}
+
+fn monitor_loop(
+    spec: ProcessSpec,
+    restart_policy: RestartPolicy,
+    child: Arc<Mutex<Child>>,
+    pid: Arc<AtomicU32>,
+    stopping: Arc<AtomicBool>,
+    panicked: Arc<AtomicBool>,
+) {
+    let mut backoff = restart_policy.initial_backoff;
+    let mut started = Instant::now();
+
+    loop {
+        let status = loop {
+            if stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            match child.lock().unwrap().try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::error!("Couldn't wait for {}: {err}", spec.name);
+                    return;
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if panicked.load(Ordering::SeqCst) {
+            tracing::error!("{} panicked and exited with {status}", spec.name);
+        } else if status.success() {
+            tracing::info!("{} exited", spec.name);
+        } else {
+            tracing::error!("{} exited with {status}", spec.name);
+        }
+
+        if !restart_policy.enabled {
+            return;
+        }
+
+        if started.elapsed() >= STABLE_RUN_TIME {
+            backoff = restart_policy.initial_backoff;
+        }
+        tracing::info!("Restarting {} in {}s", spec.name, backoff.as_secs_f32());
+        let deadline = Instant::now() + backoff;
+        while Instant::now() < deadline {
+            if stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        backoff = (backoff * 2).min(restart_policy.max_backoff);
+
+        match spawn_child(&spec, &panicked) {
+            Ok(new_child) => {
+                pid.store(new_child.id(), Ordering::SeqCst);
+                *child.lock().unwrap() = new_child;
+                started = Instant::now();
+            }
+            Err(err) => {
+                tracing::error!("Couldn't restart {}: {err}", spec.name);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Versioning of the messages sent to hot-patch clients.
+//
+// Patches are serialized `dioxus_devtools::DevserverMsg`s of the version this tool is built with. A
+// client linking another version may deserialize them wrongly or misapply the jump table without any
+// error, so clients announce their runtime version when connecting:
+//
+// ws://127.0.0.1:3100/?aslr_reference=<u64>&devtools_version=<semver>&subsecond_version=<semver>&build_id=<u64>
+//
+// Clients that don't announce their versions are checked against the versions in the `Cargo.lock`
+// of the app, read after every fat build. The versions used by this tool are sent back in the `x-devtools-version` and `x-subsecond-types`
+// headers of the handshake response and are available from `GET /protocol` of the control API.
+// The ids of the current fat builds are sent in the `x-build-ids` header, a client announcing a
+// non-zero `build_id` only receives patches stamped with that id, `build_id=0` is what clients send
+// when they weren't given one (wasm builds always do) and counts as not announced. Launched binaries
+// get their build id in the `DIOXUS_BUILD_ID` environment variable, which
+// `dioxus_cli_config::build_id()` reads, and in `SUBSECOND_BUILD_ID`.
+
+use std::path::Path;
+
+use dioxus_devtools::DevserverMsg;
+use serde::Serialize;
+
+/// Version of `dioxus-devtools` the messages are serialized with
+pub const DEVTOOLS_VERSION: &str = "0.7.0-alpha.3";
+/// Version of `subsecond-types`, which defines the jump table sent inside the messages
+pub const SUBSECOND_TYPES_VERSION: &str = "0.7.0-alpha.3";
+/// Fields of `subsecond_types::JumpTable` as serialized in `HotReloadMsg::jump_table`
+pub const JUMP_TABLE_FIELDS: &[&str] = &[
+    "lib",
+    "map",
+    "aslr_reference",
+    "new_base_address",
+    "ifunc_count",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolInfo {
+    pub devtools_version: &'static str,
+    pub subsecond_types_version: &'static str,
+    pub jump_table_fields: &'static [&'static str],
+}
+
+pub fn protocol_info() -> ProtocolInfo {
+    ProtocolInfo {
+        devtools_version: DEVTOOLS_VERSION,
+        subsecond_types_version: SUBSECOND_TYPES_VERSION,
+        jump_table_fields: JUMP_TABLE_FIELDS,
+    }
+}
+
+/// What a client announced in the query of its websocket handshake
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    pub aslr_reference: Option<u64>,
+    pub devtools_version: Option<String>,
+    pub subsecond_version: Option<String>,
+    /// Fat build the client is running
+    pub build_id: Option<u64>,
+}
+
+impl Handshake {
+    pub fn parse(query: &str) -> Self {
+        let mut handshake = Self::default();
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "aslr_reference" => handshake.aslr_reference = value.parse().ok(),
+                "devtools_version" => handshake.devtools_version = Some(value.to_string()),
+                "subsecond_version" => handshake.subsecond_version = Some(value.to_string()),
+                // 0 is the default of clients that weren't given a build id
+                "build_id" => handshake.build_id = value.parse().ok().filter(|id| *id != 0),
+                _ => {}
+            }
+        }
+        handshake
+    }
+
+    /// Fills the versions the client didn't announce with the ones of the app
+    pub fn or_app_versions(mut self, app: &AppVersions) -> Self {
+        if self.devtools_version.is_none() {
+            self.devtools_version = app.devtools_version.clone();
+        }
+        if self.subsecond_version.is_none() {
+            self.subsecond_version = app.subsecond_version.clone();
+        }
+        self
+    }
+
+    /// Returns why patches of this tool can't be applied by the client. Versions that are unknown
+    /// are assumed to be compatible.
+    pub fn incompatibility(&self) -> Option<String> {
+        let mismatches = [
+            ("dioxus-devtools", &self.devtools_version, DEVTOOLS_VERSION),
+            (
+                "subsecond",
+                &self.subsecond_version,
+                SUBSECOND_TYPES_VERSION,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, version, expected)| {
+            let version = version.as_ref()?;
+            (!is_compatible(version, expected))
+                .then(|| format!("{name} {version} (expected {expected})"))
+        })
+        .collect::<Vec<_>>();
+
+        if mismatches.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "client uses incompatible {}, update the app's dependencies to match",
+                mismatches.join(" and ")
+            ))
+        }
+    }
+}
+
+/// Versions of `dioxus-devtools` and `subsecond` the app links according to its `Cargo.lock`, they
+/// stand in for the versions of clients that don't announce theirs
+#[derive(Debug, Clone, Default)]
+pub struct AppVersions {
+    pub devtools_version: Option<String>,
+    pub subsecond_version: Option<String>,
+}
+
+impl AppVersions {
+    /// Reads the lockfile of the workspace containing `project_dir`
+    pub fn from_lockfile(project_dir: &Path) -> Self {
+        let Some(lockfile) = project_dir
+            .ancestors()
+            .map(|dir| dir.join("Cargo.lock"))
+            .find(|path| path.is_file())
+        else {
+            return Self::default();
+        };
+        let Some(lock) = std::fs::read_to_string(&lockfile)
+            .ok()
+            .and_then(|contents| contents.parse::<toml::Table>().ok())
+        else {
+            tracing::warn!("Couldn't parse {}", lockfile.display());
+            return Self::default();
+        };
+
+        let packages = lock
+            .get("package")
+            .and_then(|p| p.as_array())
+            .cloned()
+            .unwrap_or_default();
+        // with several versions in the lockfile an incompatible one is reported
+        let version_of = |name: &str, expected: &str| {
+            let versions = packages
+                .iter()
+                .filter(|p| p.get("name").and_then(|n| n.as_str()) == Some(name))
+                .filter_map(|p| p.get("version").and_then(|v| v.as_str()))
+                .collect::<Vec<_>>();
+            versions
+                .iter()
+                .find(|version| !is_compatible(version, expected))
+                .or(versions.first())
+                .map(|version| version.to_string())
+        };
+        Self {
+            devtools_version: version_of("dioxus-devtools", DEVTOOLS_VERSION),
+            subsecond_version: version_of("subsecond", SUBSECOND_TYPES_VERSION),
+        }
+    }
+}
+
+/// Whether a client running `client_build` can apply `msg`, messages that aren't stamped and
+/// clients that didn't announce their build are let through
+pub fn is_for_build(msg: &DevserverMsg, client_build: Option<u64>) -> bool {
+    match (msg, client_build) {
+        (DevserverMsg::HotReload(msg), Some(client_build)) => msg
+            .for_build_id
+            .is_none_or(|build_id| build_id == client_build),
+        _ => true,
+    }
+}
+
+/// Same release line, pre-releases have to match exactly since their format changes between alphas
+pub fn is_compatible(version: &str, expected: &str) -> bool {
+    if expected.contains('-') || version.contains('-') {
+        return version == expected;
+    }
+    let minor = |v: &str| v.split('.').take(2).collect::<Vec<_>>().join(".");
+    minor(version) == minor(expected)
+}
@@ -0,0 +1,248 @@
+// Small HTTP proxy that sits in front of the SSR server.
+//
+// It forwards every request to the running server binary, injects the hot-patch client script into
+// HTML responses and serves a "server restarting" page to page loads (a plain 503 to anything else)
+// while the server is being rebuilt or hasn't started listening yet. This is intentionally dumb: one request per connection, no keep-alive.
+
+use std::{
+    io::{BufReader, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use crate::http::{HttpHead, read_body, write_response};
+
+const CLIENT_SCRIPT_PATH: &str = "/__subsecond/client.js";
+const STATUS_PATH: &str = "/__subsecond/status";
+
+/// State of the site shared between builders and the proxy.
+///
+/// Every fat build of either side bumps `generation` once finished, clients compare it with the
+/// generation they were loaded with to know when they need to reload the page.
+#[derive(Default)]
+pub struct SiteState {
+    rebuilding: AtomicUsize,
+    generation: AtomicU64,
+    /// Build id of the wasm module currently served, 0 before the first fat build
+    client_build_id: AtomicU64,
+}
+
+impl SiteState {
+    pub fn start_rebuild(&self) {
+        self.rebuilding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn finish_rebuild(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.rebuilding.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn is_rebuilding(&self) -> bool {
+        self.rebuilding.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn set_client_build_id(&self, build_id: u64) {
+        self.client_build_id.store(build_id, Ordering::SeqCst);
+    }
+
+    pub fn client_build_id(&self) -> u64 {
+        self.client_build_id.load(Ordering::SeqCst)
+    }
+}
+
+pub struct ProxyServer {
+    addr: String,
+    upstream_addr: String,
+    hotpatch_addr: String,
+    site_state: Arc<SiteState>,
+}
+
+impl ProxyServer {
+    pub fn new(
+        addr: &str,
+        upstream_addr: &str,
+        hotpatch_addr: &str,
+        site_state: Arc<SiteState>,
+    ) -> Self {
+        Self {
+            addr: addr.to_string(),
+            upstream_addr: upstream_addr.to_string(),
+            hotpatch_addr: hotpatch_addr.to_string(),
+            site_state,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let listener = TcpListener::bind(&self.addr).unwrap();
+        tracing::info!(
+            "Proxy listening on http://{}, forwarding to http://{}",
+            self.addr,
+            self.upstream_addr
+        );
+        for stream in listener.incoming().flatten() {
+            let upstream_addr = self.upstream_addr.clone();
+            let hotpatch_addr = self.hotpatch_addr.clone();
+            let site_state = Arc::clone(&self.site_state);
+            std::thread::spawn(move || {
+                if let Err(err) =
+                    Self::handle_connection(stream, &upstream_addr, &hotpatch_addr, &site_state)
+                {
+                    tracing::trace!("Proxy connection error: {err}");
+                }
+            });
+        }
+    }
+
+    fn handle_connection(
+        client: TcpStream,
+        upstream_addr: &str,
+        hotpatch_addr: &str,
+        site_state: &SiteState,
+    ) -> std::io::Result<()> {
+        let mut client_writer = client.try_clone()?;
+        let mut client_reader = BufReader::new(client);
+        let Some(mut request) = HttpHead::read(&mut client_reader) else {
+            return Ok(());
+        };
+
+        match request.path() {
+            CLIENT_SCRIPT_PATH => {
+                let script = include_str!("client/hotpatch.js")
+                    .replace("{{HOTPATCH_ADDR}}", hotpatch_addr)
+                    .replace("{{GENERATION}}", &site_state.generation().to_string())
+                    .replace("{{BUILD_ID}}", &site_state.client_build_id().to_string());
+                return write_response(
+                    &mut client_writer,
+                    "200 OK",
+                    "application/javascript",
+                    script.as_bytes(),
+                );
+            }
+            STATUS_PATH => {
+                let status = serde_json::json!({
+                    "rebuilding": site_state.is_rebuilding(),
+                    "generation": site_state.generation(),
+                    "build_id": site_state.client_build_id(),
+                });
+                return write_response(
+                    &mut client_writer,
+                    "200 OK",
+                    "application/json",
+                    status.to_string().as_bytes(),
+                );
+            }
+            _ => {}
+        }
+
+        if site_state.is_rebuilding() {
+            return write_unavailable(&mut client_writer, &request);
+        }
+
+        let Ok(mut upstream) = TcpStream::connect(upstream_addr) else {
+            // the server was killed or didn't bind its port yet
+            return write_unavailable(&mut client_writer, &request);
+        };
+
+        // Websockets and other upgrades (e.g. leptos' own reload socket) are simply tunneled
+        if request.is_upgrade() {
+            upstream.write_all(&request.to_bytes())?;
+            let mut upstream_reader = upstream.try_clone()?;
+            let mut client_to_upstream = upstream.try_clone()?;
+            let tunnel = std::thread::spawn(move || {
+                _ = std::io::copy(&mut client_reader, &mut client_to_upstream);
+                _ = client_to_upstream.shutdown(Shutdown::Write);
+            });
+            _ = std::io::copy(&mut upstream_reader, &mut client_writer);
+            _ = client_writer.shutdown(Shutdown::Write);
+            _ = tunnel.join();
+            return Ok(());
+        }
+
+        let body = read_body(&mut client_reader, &request, false)?;
+        request.remove_header("connection");
+        request.remove_header("keep-alive");
+        request.remove_header("transfer-encoding");
+        request.remove_header("content-length");
+        // we need plain text to be able to inject the script
+        request.remove_header("accept-encoding");
+        request
+            .headers
+            .push(("Connection".to_string(), "close".to_string()));
+        if !body.is_empty() {
+            request
+                .headers
+                .push(("Content-Length".to_string(), body.len().to_string()));
+        }
+        upstream.write_all(&request.to_bytes())?;
+        upstream.write_all(&body)?;
+
+        let mut upstream_reader = BufReader::new(upstream);
+        let Some(mut response) = HttpHead::read(&mut upstream_reader) else {
+            return write_unavailable(&mut client_writer, &request);
+        };
+
+        let is_html = response
+            .header("content-type")
+            .is_some_and(|v| v.starts_with("text/html"));
+        // the upstream closes the connection, so anything else is passed through until then
+        if !is_html || response.is_bodyless_response(&request) {
+            client_writer.write_all(&response.to_bytes())?;
+            std::io::copy(&mut upstream_reader, &mut client_writer)?;
+            return Ok(());
+        }
+
+        let body = read_body(&mut upstream_reader, &response, true)?;
+        let html = inject_client_script(&String::from_utf8_lossy(&body));
+        response.remove_header("content-length");
+        response.remove_header("transfer-encoding");
+        response.remove_header("connection");
+        response
+            .headers
+            .push(("Content-Length".to_string(), html.len().to_string()));
+        response
+            .headers
+            .push(("Connection".to_string(), "close".to_string()));
+        client_writer.write_all(&response.to_bytes())?;
+        client_writer.write_all(html.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn inject_client_script(html: &str) -> String {
+    let script = format!(r#"<script src="{CLIENT_SCRIPT_PATH}"></script>"#);
+    for tag in ["</head>", "</body>"] {
+        if let Some(idx) = html.find(tag) {
+            let mut out = html.to_string();
+            out.insert_str(idx, &script);
+            return out;
+        }
+    }
+    format!("{html}{script}")
+}
+
+/// Answers a request the server can't handle right now, only pages get the restarting page which
+/// reloads itself
+fn write_unavailable(writer: &mut impl Write, request: &HttpHead) -> std::io::Result<()> {
+    if !request.is_navigation() {
+        return write_response(
+            writer,
+            "503 Service Unavailable",
+            "text/plain; charset=utf-8",
+            b"The server is restarting",
+        );
+    }
+    let page = inject_client_script(include_str!("client/restarting.html"));
+    write_response(
+        writer,
+        "503 Service Unavailable",
+        "text/html; charset=utf-8",
+        page.as_bytes(),
+    )
+}
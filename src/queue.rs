@@ -0,0 +1,90 @@
+// Queue of build commands that coalesces commands arriving while a build is running.
+//
+// Only a single pending build is kept: `FatRebuild` wins over `Fat` which wins over `Thin`. Rollbacks
+// are counted separately and run before the pending build, each one undoes a patch. A newer
+// command cancels the running build if it makes its result useless, e.g. a thin build is superseded
+// by any other command, but a fat build is never cancelled by a thin one.
+
+use std::sync::{Arc, Condvar, Mutex, mpsc::Receiver};
+
+use crate::{builder::BuildCommand, cancel::CancelToken};
+
+#[derive(Default)]
+struct QueueState {
+    pending: Option<BuildCommand>,
+    /// Rollbacks requested, they never coalesce with builds
+    rollbacks: usize,
+    running: Option<(BuildCommand, CancelToken)>,
+    closed: bool,
+}
+
+#[derive(Default)]
+pub struct BuildQueue {
+    state: Mutex<QueueState>,
+    condvar: Condvar,
+}
+
+impl BuildQueue {
+    /// Forwards commands from `receiver` into a new queue on a separate thread
+    pub fn spawn(receiver: Receiver<BuildCommand>) -> Arc<Self> {
+        let queue = Arc::new(Self::default());
+        let forward_queue = Arc::clone(&queue);
+        std::thread::spawn(move || {
+            while let Ok(command) = receiver.recv() {
+                forward_queue.push(command);
+            }
+            forward_queue.close();
+        });
+        queue
+    }
+
+    pub fn push(&self, command: BuildCommand) {
+        let mut state = self.state.lock().unwrap();
+        if command == BuildCommand::Rollback {
+            state.rollbacks += 1;
+        } else {
+            state.pending = Some(match state.pending {
+                Some(pending) => pending.max(command),
+                None => command,
+            });
+        }
+        if let Some((running, cancel)) = &state.running
+            && command.supersedes(*running)
+        {
+            tracing::info!("Cancelling {running:?} build, superseded by {command:?}");
+            cancel.cancel();
+        }
+        self.condvar.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.condvar.notify_all();
+    }
+
+    /// Blocks until there is a command to run, returns `None` once the sender is gone
+    pub fn next(&self) -> Option<(BuildCommand, CancelToken)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let command = if state.rollbacks > 0 {
+                state.rollbacks -= 1;
+                Some(BuildCommand::Rollback)
+            } else {
+                state.pending.take()
+            };
+            if let Some(command) = command {
+                let cancel = CancelToken::default();
+                state.running = Some((command, cancel.clone()));
+                return Some((command, cancel));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.condvar.wait(state).unwrap();
+        }
+    }
+
+    pub fn finish(&self) {
+        self.state.lock().unwrap().running = None;
+    }
+}
@@ -0,0 +1,212 @@
+// `build` subcommand: a deployable site without any of the hot-patch machinery.
+//
+// Server and client are built with a plain `cargo build`, so the linker removes dead code as usual
+// and nothing is exported for patches. The client goes through wasm-bindgen without
+// `prepare_wasm_base_module`, optionally through `wasm-opt`, and is written to the site pkg dir,
+// with content-hashed names by default (see `hash_files`). The assets dir is copied into the site root.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context as _, bail};
+use target_lexicon::OperatingSystem;
+use wasm_bindgen_cli_support::Bindgen;
+
+use crate::{
+    config::BindgenTarget,
+    context::{Context, copy_dir_all},
+    hash_files::HashedFiles,
+};
+
+pub struct ReleaseOptions {
+    pub release: bool,
+    /// `wasm-opt` optimization level, e.g. `z`, `s` or `3`
+    pub wasm_opt: Option<String>,
+    pub hash_files: bool,
+    /// Assets dir of the project, relative to the project dir
+    pub assets_dir: Option<String>,
+}
+
+pub fn build_site(
+    mut server: Context,
+    mut client: Context,
+    options: &ReleaseOptions,
+) -> anyhow::Result<()> {
+    if options.release {
+        for ctx in [&mut server, &mut client] {
+            ctx.profile_name = "release".to_string();
+            ctx.profile_dir = "release".to_string();
+        }
+    }
+
+    // the server looks for the client files under the name wasm-bindgen writes them with
+    let out_name = client.bindgen_out_name();
+    tracing::info!("Building the server");
+    let server_exe = cargo_build(&server, &out_name, options)?;
+    tracing::info!("Building the client");
+    let client_wasm = cargo_build(&client, &out_name, options)?;
+
+    tracing::info!("Running wasm-bindgen");
+    let bindgen_dir = bindgen(&client, &client_wasm)?;
+    let bg_wasm = bindgen_dir.join(format!("{out_name}_bg.wasm"));
+    if let Some(level) = &options.wasm_opt {
+        tracing::info!("Running wasm-opt -O{level}");
+        wasm_opt(&bg_wasm, level)?;
+    }
+
+    let pkg_path = client.pkg_out_path();
+    _ = std::fs::remove_dir_all(&pkg_path);
+    std::fs::create_dir_all(&pkg_path)?;
+
+    if client.bindgen.target != BindgenTarget::Web {
+        // the generated files import each other by name
+        if options.hash_files {
+            tracing::warn!("File names are only hashed for the web target");
+        }
+        copy_dir_all(&bindgen_dir, &pkg_path)?;
+    } else {
+        let js = std::fs::read(bindgen_dir.join(format!("{out_name}.js")))?;
+        let wasm = std::fs::read(&bg_wasm)?;
+        if options.hash_files {
+            let mut hashed = HashedFiles::default();
+            let js_name = hashed.write(&pkg_path, &out_name, "js", &js)?;
+            let wasm_name = hashed.write(&pkg_path, &out_name, "wasm", &wasm)?;
+            // Leptos looks for it next to the server binary
+            hashed.write_hash_txt(server_exe.parent().unwrap())?;
+            hashed.write_manifest(&pkg_path)?;
+            tracing::info!("Wrote {js_name} and {wasm_name}");
+        } else {
+            std::fs::write(pkg_path.join(format!("{out_name}.js")), js)?;
+            std::fs::write(pkg_path.join(format!("{out_name}.wasm")), wasm)?;
+        }
+        if client.bindgen.typescript {
+            let name = format!("{out_name}.d.ts");
+            std::fs::copy(bindgen_dir.join(&name), pkg_path.join(&name))?;
+        }
+        let snippets = bindgen_dir.join("snippets");
+        if snippets.exists() {
+            copy_dir_all(&snippets, &pkg_path.join("snippets"))?;
+        }
+    }
+
+    if let Some(assets_dir) = &options.assets_dir {
+        let assets_dir = client.working_dir.join(assets_dir);
+        if assets_dir.exists() {
+            copy_dir_all(&assets_dir, &client.site_dir_path())?;
+        }
+    }
+
+    println!("Server: {}", server_exe.display());
+    println!("Site:   {}", client.site_dir_path().display());
+    if options.hash_files && client.bindgen.target == BindgenTarget::Web {
+        println!("Run the server with LEPTOS_HASH_FILES=true to load the hashed files");
+    }
+    Ok(())
+}
+
+/// Plain `cargo build` of the binary or library of `ctx`, returns the built artifact
+fn cargo_build(
+    ctx: &Context,
+    output_name: &str,
+    options: &ReleaseOptions,
+) -> anyhow::Result<PathBuf> {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(&ctx.working_dir)
+        .arg("build")
+        .arg("--profile")
+        .arg(&ctx.profile_name)
+        .arg("-p")
+        .arg(&ctx.package)
+        .arg("--target")
+        .arg(ctx.triple.to_string());
+
+    if ctx.no_default_features {
+        command.arg("--no-default-features");
+    }
+    if let Some(bin_name) = &ctx.bin {
+        command.arg("--bin").arg(bin_name);
+    }
+    if ctx.lib {
+        command.arg("--lib");
+    }
+    if !ctx.features.is_empty() {
+        command.arg("--features").arg(ctx.features.join(","));
+    }
+
+    let rust_flags = ctx
+        .rust_flags
+        .iter()
+        .map(|flag| {
+            if flag.starts_with("cfg") {
+                "--".to_string() + flag
+            } else {
+                flag.clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    if !rust_flags.is_empty() {
+        command.env("RUSTFLAGS", rust_flags.join(" "));
+    }
+    command
+        .env("LEPTOS_OUTPUT_NAME", output_name)
+        .env("LEPTOS_SITE_ROOT", &ctx.site_dir)
+        .env("LEPTOS_SITE_PKG_DIR", &ctx.site_pkg_dir);
+    if options.hash_files {
+        command.env("LEPTOS_HASH_FILES", "true");
+    }
+
+    let status = command.status().context("failed to run cargo")?;
+    if !status.success() {
+        bail!("cargo build of {} failed", ctx.final_binary_name());
+    }
+
+    let artifact = if ctx.lib {
+        format!("{}.wasm", ctx.package.replace('-', "_"))
+    } else if ctx.triple.operating_system == OperatingSystem::Windows {
+        format!("{}.exe", ctx.final_binary_name())
+    } else {
+        ctx.final_binary_name()
+    };
+    Ok(ctx.target_triple_profile_dir().join(artifact))
+}
+
+/// wasm-bindgen with the configured target, without the exports and debug info hot-patching needs
+fn bindgen(ctx: &Context, wasm: &Path) -> anyhow::Result<PathBuf> {
+    let out_dir = ctx.target_dir.join("wasm-bindgen-release");
+    _ = std::fs::remove_dir_all(&out_dir);
+    std::fs::create_dir_all(&out_dir)?;
+
+    let mut bindgen = Bindgen::new();
+    bindgen
+        .input_path(wasm)
+        .out_name(&ctx.bindgen_out_name())
+        .typescript(ctx.bindgen.typescript)
+        .debug(false)
+        .keep_debug(false);
+    match ctx.bindgen.target {
+        BindgenTarget::Web => bindgen.web(true),
+        BindgenTarget::Bundler => bindgen.bundler(true),
+        BindgenTarget::NoModules => bindgen.no_modules(true),
+        BindgenTarget::Deno => bindgen.deno(true),
+    }?;
+    bindgen.generate(&out_dir)?;
+    Ok(out_dir)
+}
+
+fn wasm_opt(wasm: &Path, level: &str) -> anyhow::Result<()> {
+    let status = Command::new("wasm-opt")
+        .arg(format!("-O{level}"))
+        .arg("--all-features")
+        .arg(wasm)
+        .arg("-o")
+        .arg(wasm)
+        .status()
+        .context("failed to run wasm-opt, install binaryen or drop --wasm-opt")?;
+    if !status.success() {
+        bail!("wasm-opt failed");
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// Debugging aids for failed patches.
+//
+// With `--keep-temps` every thin build keeps its link inputs and outputs in
+// `target/hotpatch-debug/<binary>/<millis>/` instead of deleting them:
+//
+// - `rustc_args.json`, `link_args.txt`   arguments captured during the fat build and this build
+// - `linker_command.txt`                  the generated thin linker command line
+// - `*.rcgu.o`, `stub.o`                  objects of the patch and the resolved symbol stub
+// - `linker_output.txt`, `error.txt`      linker stderr and the error the build failed with
+// - the patch binary and `jump_table.json`
+//
+// The `report` subcommand bundles the latest of these directories into a `.tar.gz` to attach to an
+// issue.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, bail};
+use flate2::{Compression, write::GzEncoder};
+use serde::Serialize;
+
+use crate::context::Context;
+
+const DEBUG_DIR: &str = "hotpatch-debug";
+
+fn debug_root(target_dir: &Path) -> PathBuf {
+    target_dir.join(DEBUG_DIR)
+}
+
+/// Directory where the temps of the thin build started at `time_start` are kept
+pub fn debug_dir(ctx: &Context, time_start: SystemTime) -> PathBuf {
+    let millis = time_start
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let dir = debug_root(&ctx.target_dir)
+        .join(ctx.final_binary_name())
+        .join(millis.to_string());
+    _ = std::fs::create_dir_all(&dir);
+    dir
+}
+
+/// Writes `contents` into the debug dir of the build if temps are kept
+pub fn keep_text(ctx: &Context, time_start: SystemTime, name: &str, contents: impl AsRef<[u8]>) {
+    if ctx.keep_temps {
+        _ = std::fs::write(debug_dir(ctx, time_start).join(name), contents);
+    }
+}
+
+pub fn keep_json(ctx: &Context, time_start: SystemTime, name: &str, value: &impl Serialize) {
+    if ctx.keep_temps {
+        keep_text(
+            ctx,
+            time_start,
+            name,
+            serde_json::to_vec_pretty(value).unwrap_or_default(),
+        );
+    }
+}
+
+pub fn keep_error(ctx: &Context, time_start: SystemTime, err: &impl Display) {
+    keep_text(ctx, time_start, "error.txt", format!("{err:#}"));
+}
+
+/// Moves `file` into the debug dir if temps are kept, otherwise deletes it
+pub fn keep_or_remove(ctx: &Context, time_start: SystemTime, file: &Path) {
+    if !ctx.keep_temps {
+        _ = std::fs::remove_file(file);
+        return;
+    }
+    let Some(name) = file.file_name() else {
+        return;
+    };
+    let kept = debug_dir(ctx, time_start).join(name);
+    // objects usually live on the same filesystem as the target dir
+    if std::fs::rename(file, &kept).is_err() {
+        _ = std::fs::copy(file, &kept);
+        _ = std::fs::remove_file(file);
+    }
+}
+
+/// Copies `file` into the debug dir if temps are kept
+pub fn keep_copy(ctx: &Context, time_start: SystemTime, file: &Path) {
+    if let (true, Some(name)) = (ctx.keep_temps, file.file_name()) {
+        _ = std::fs::copy(file, debug_dir(ctx, time_start).join(name));
+    }
+}
+
+/// Latest build directory of every binary in the debug dir
+fn latest_builds(target_dir: &Path) -> Vec<PathBuf> {
+    let Ok(binaries) = std::fs::read_dir(debug_root(target_dir)) else {
+        return vec![];
+    };
+    binaries
+        .flatten()
+        .filter_map(|binary| {
+            std::fs::read_dir(binary.path())
+                .ok()?
+                .flatten()
+                .filter_map(|build| {
+                    let millis = build.file_name().to_str()?.parse::<u128>().ok()?;
+                    Some((millis, build.path()))
+                })
+                .max_by_key(|(millis, _)| *millis)
+                .map(|(_, path)| path)
+        })
+        .collect()
+}
+
+/// Bundles build directories and extra files (logs, event logs) into a `.tar.gz`, returns its path.
+/// Without `builds` the latest build of every binary is used.
+pub fn create_report(
+    target_dir: &Path,
+    builds: &[PathBuf],
+    extra_files: &[PathBuf],
+    out: Option<PathBuf>,
+) -> anyhow::Result<PathBuf> {
+    let builds = if builds.is_empty() {
+        latest_builds(target_dir)
+    } else {
+        builds.to_vec()
+    };
+    if builds.is_empty() {
+        bail!(
+            "No kept builds found in {}, reproduce the failure with --keep-temps first",
+            debug_root(target_dir).display()
+        );
+    }
+
+    let out = out.unwrap_or_else(|| {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        target_dir.join(format!("hotpatch-report-{millis}.tar.gz"))
+    });
+    let file = File::create(&out).with_context(|| format!("failed to create {}", out.display()))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    for build in &builds {
+        // keep the `<binary>/<millis>` part so builds of both sides don't collide
+        let name = build
+            .strip_prefix(debug_root(target_dir))
+            .unwrap_or(build)
+            .to_path_buf();
+        archive
+            .append_dir_all(&name, build)
+            .with_context(|| format!("failed to add {}", build.display()))?;
+    }
+    for file in extra_files {
+        let name = Path::new("logs").join(file.file_name().unwrap_or_default());
+        archive
+            .append_path_with_name(file, &name)
+            .with_context(|| format!("failed to add {}", file.display()))?;
+    }
+    archive.into_inner()?.finish()?;
+
+    Ok(out)
+}
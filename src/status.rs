@@ -0,0 +1,537 @@
+// State of the dev session shared between builders, the websocket server and the control API:
+// what each builder is doing, which clients are connected and which patches were sent.
+
+use std::{
+    collections::VecDeque,
+    process::{Command, ExitStatus},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    builder::BuildCommand,
+    cancel::CancelToken,
+    events::{BuildEvent, BuildOutcome, EventLog},
+    protocol::AppVersions,
+};
+
+const PATCH_HISTORY_LEN: usize = 100;
+const LOG_LEN: usize = 1000;
+const MAX_COMPILER_MESSAGES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildPhase {
+    Idle,
+    Compiling,
+    /// Resolving symbols of the patch against the running binary
+    Stub,
+    Linking,
+    JumpTable,
+    Bindgen,
+    Sending,
+}
+
+impl std::fmt::Display for BuildPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            BuildPhase::Idle => "idle",
+            BuildPhase::Compiling => "compiling",
+            BuildPhase::Stub => "stub",
+            BuildPhase::Linking => "linking",
+            BuildPhase::JumpTable => "jump table",
+            BuildPhase::Bindgen => "bindgen",
+            BuildPhase::Sending => "sending",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub phase: BuildPhase,
+    pub started_at_ms: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Sizes and counts collected while building, `None` when not applicable to the build
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildMetrics {
+    /// Object files of the top-level crate produced by rustc
+    pub objects: Option<usize>,
+    pub patch_size_bytes: Option<u64>,
+    /// Functions replaced by the patch
+    pub jump_table_entries: Option<usize>,
+    /// Symbols of the fat binary patches are resolved against
+    pub fat_symbols: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildSummary {
+    pub command: BuildCommand,
+    pub finished_at_ms: u64,
+    pub elapsed_ms: u64,
+    pub phases: Vec<PhaseTiming>,
+    pub metrics: BuildMetrics,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub builder: String,
+    pub command: BuildCommand,
+    pub message: String,
+    /// What rustc reported during the failed build
+    pub compiler_messages: Vec<CompilerMessage>,
+    pub at_ms: u64,
+}
+
+/// A diagnostic printed by rustc
+#[derive(Debug, Clone, Serialize)]
+pub struct CompilerMessage {
+    pub level: String,
+    pub message: String,
+    /// `file:line:column` of the primary span
+    pub location: Option<String>,
+    /// The message as rustc prints it, without colors
+    pub rendered: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuilderStatus {
+    pub name: String,
+    pub target: String,
+    pub phase: BuildPhase,
+    /// Id of the last successful fat build, see [`crate::fingerprint::build_id`]
+    pub build_id: Option<u64>,
+    pub last_build: Option<BuildSummary>,
+    pub last_error: Option<Diagnostic>,
+    /// Phases of the build in progress
+    #[serde(skip)]
+    phases: Vec<PhaseTiming>,
+    #[serde(skip)]
+    metrics: BuildMetrics,
+    /// Diagnostics of the build in progress
+    #[serde(skip)]
+    compiler_messages: Vec<CompilerMessage>,
+    #[serde(skip)]
+    phase_started: Option<(Instant, u64)>,
+    #[serde(skip)]
+    build_started: Option<(Instant, u64)>,
+}
+
+impl BuilderStatus {
+    fn finish_phase(&mut self) {
+        if let Some((started, started_at_ms)) = self.phase_started.take()
+            && self.phase != BuildPhase::Idle
+        {
+            self.phases.push(PhaseTiming {
+                phase: self.phase,
+                started_at_ms,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub aslr_reference: Option<u64>,
+    /// Version of dioxus-devtools announced by the client
+    pub devtools_version: Option<String>,
+    pub build_id: Option<u64>,
+    pub connected_at_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchRecord {
+    pub builder: String,
+    pub lib: String,
+    pub for_build_id: Option<u64>,
+    pub for_pid: Option<u32>,
+    pub sent_at_ms: u64,
+}
+
+#[derive(Default)]
+pub struct DevState {
+    builders: Mutex<Vec<BuilderStatus>>,
+    clients: Mutex<Vec<ClientInfo>>,
+    patches: Mutex<VecDeque<PatchRecord>>,
+    next_client_id: AtomicU64,
+    server_log: Mutex<VecDeque<String>>,
+    tool_log: Mutex<VecDeque<String>>,
+    /// When set, output of launched binaries is only kept in `server_log` instead of being printed
+    capture_output: AtomicBool,
+    event_log: Mutex<Option<EventLog>>,
+    /// Versions the app links, see [`AppVersions`]
+    app_versions: Mutex<AppVersions>,
+}
+
+/// Handle used by a builder to update its own entry in [`DevState`]
+#[derive(Clone)]
+pub struct BuilderHandle {
+    state: Arc<DevState>,
+    index: usize,
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl DevState {
+    pub fn register_builder(self: &Arc<Self>, name: String, target: String) -> BuilderHandle {
+        let mut builders = self.builders.lock().unwrap();
+        builders.push(BuilderStatus {
+            name,
+            target,
+            phase: BuildPhase::Idle,
+            build_id: None,
+            last_build: None,
+            last_error: None,
+            phases: vec![],
+            metrics: BuildMetrics::default(),
+            compiler_messages: vec![],
+            phase_started: None,
+            build_started: None,
+        });
+        BuilderHandle {
+            state: Arc::clone(self),
+            index: builders.len() - 1,
+        }
+    }
+
+    pub fn builders(&self) -> Vec<BuilderStatus> {
+        self.builders.lock().unwrap().clone()
+    }
+
+    /// Ids of the current fat builds of all builders
+    pub fn build_ids(&self) -> Vec<u64> {
+        self.builders
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|b| b.build_id)
+            .collect()
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.builders
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|b| b.last_error.clone())
+            .collect()
+    }
+
+    pub fn add_client(
+        &self,
+        addr: String,
+        aslr_reference: Option<u64>,
+        devtools_version: Option<String>,
+        build_id: Option<u64>,
+    ) -> u64 {
+        let id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        self.clients.lock().unwrap().push(ClientInfo {
+            id,
+            addr,
+            aslr_reference,
+            devtools_version,
+            build_id,
+            connected_at_ms: unix_millis(),
+        });
+        id
+    }
+
+    pub fn remove_client(&self, id: u64) {
+        self.clients.lock().unwrap().retain(|c| c.id != id);
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    pub fn patches(&self) -> Vec<PatchRecord> {
+        self.patches.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn set_capture_output(&self, capture: bool) {
+        self.capture_output.store(capture, Ordering::SeqCst);
+    }
+
+    /// Output line of a launched binary, printed with a colored prefix unless output is captured
+    pub fn server_output(&self, name: &str, color: &str, line: &str) {
+        if !self.capture_output.load(Ordering::SeqCst) {
+            println!("{color}[{name}]\x1b[0m {line}");
+        }
+        push_line(&self.server_log, format!("[{name}] {line}"));
+    }
+
+    pub fn server_log(&self) -> Vec<String> {
+        self.server_log.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn push_tool_log(&self, line: String) {
+        push_line(&self.tool_log, line);
+    }
+
+    pub fn tool_log(&self) -> Vec<String> {
+        self.tool_log.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn set_app_versions(&self, versions: AppVersions) {
+        *self.app_versions.lock().unwrap() = versions;
+    }
+
+    pub fn app_versions(&self) -> AppVersions {
+        self.app_versions.lock().unwrap().clone()
+    }
+
+    pub fn set_event_log(&self, event_log: EventLog) {
+        *self.event_log.lock().unwrap() = Some(event_log);
+    }
+
+    fn record_event(&self, builder_index: usize, event: &BuildEvent) {
+        if let Some(event_log) = self.event_log.lock().unwrap().as_mut() {
+            event_log.record(builder_index, event);
+        }
+    }
+}
+
+fn push_line(log: &Mutex<VecDeque<String>>, line: String) {
+    let mut log = log.lock().unwrap();
+    if log.len() == LOG_LEN {
+        log.pop_front();
+    }
+    log.push_back(line);
+}
+
+impl BuilderHandle {
+    fn update(&self, f: impl FnOnce(&mut BuilderStatus)) {
+        f(&mut self.state.builders.lock().unwrap()[self.index]);
+    }
+
+    pub fn name(&self) -> String {
+        self.state.builders.lock().unwrap()[self.index].name.clone()
+    }
+
+    pub fn dev_state(&self) -> Arc<DevState> {
+        Arc::clone(&self.state)
+    }
+
+    /// Starts a new phase, a build starts when moving out of `Idle` and ends when moving back to it
+    pub fn set_phase(&self, phase: BuildPhase) {
+        self.update(|b| {
+            b.finish_phase();
+            if b.phase == BuildPhase::Idle {
+                b.phases.clear();
+                b.metrics = BuildMetrics::default();
+                b.compiler_messages.clear();
+                b.build_started = Some((Instant::now(), unix_millis()));
+            }
+            b.phase = phase;
+            b.phase_started = Some((Instant::now(), unix_millis()));
+        });
+    }
+
+    pub fn set_build_id(&self, build_id: u64) {
+        self.update(|b| b.build_id = Some(build_id));
+    }
+
+    /// Updates the metrics of the build in progress
+    pub fn metrics(&self, f: impl FnOnce(&mut BuildMetrics)) {
+        self.update(|b| f(&mut b.metrics));
+    }
+
+    pub fn succeeded(&self, command: BuildCommand) {
+        let event = self.finish(command, BuildOutcome::Succeeded, None);
+        self.update(|b| {
+            b.last_error = None;
+            b.last_build = Some(BuildSummary {
+                command,
+                finished_at_ms: event.started_at_ms + event.elapsed_ms,
+                elapsed_ms: event.elapsed_ms,
+                phases: event.phases,
+                metrics: event.metrics,
+            });
+        });
+    }
+
+    pub fn failed(&self, command: BuildCommand, err: &anyhow::Error) {
+        let message = format!("{err:#}");
+        self.finish(command, BuildOutcome::Failed, Some(message.clone()));
+        self.update(|b| {
+            b.last_error = Some(Diagnostic {
+                builder: b.name.clone(),
+                command,
+                message,
+                compiler_messages: std::mem::take(&mut b.compiler_messages),
+                at_ms: unix_millis(),
+            });
+        });
+    }
+
+    /// The build was cancelled or its result was thrown away
+    pub fn cancelled(&self, command: BuildCommand) {
+        self.finish(command, BuildOutcome::Cancelled, None);
+    }
+
+    /// Moves back to `Idle` and records the finished build in the event log
+    fn finish(
+        &self,
+        command: BuildCommand,
+        outcome: BuildOutcome,
+        error: Option<String>,
+    ) -> BuildEvent {
+        let mut event = None;
+        self.update(|b| {
+            b.finish_phase();
+            b.phase = BuildPhase::Idle;
+            let (elapsed_ms, started_at_ms) = b
+                .build_started
+                .take()
+                .map(|(started, at_ms)| (started.elapsed().as_millis() as u64, at_ms))
+                .unwrap_or((0, unix_millis()));
+            event = Some(BuildEvent {
+                builder: b.name.clone(),
+                target: b.target.clone(),
+                command,
+                outcome,
+                started_at_ms,
+                elapsed_ms,
+                phases: std::mem::take(&mut b.phases),
+                metrics: std::mem::take(&mut b.metrics),
+                error,
+            });
+        });
+        let event = event.unwrap();
+        self.state.record_event(self.index, &event);
+        event
+    }
+
+    /// Runs a cargo or rustc command of the build and keeps its diagnostics for `/diagnostics`. Its
+    /// output is printed, or with `--tui` sent to the build log instead of drawing over the UI.
+    pub fn run(&self, cancel: &CancelToken, cmd: &mut Command) -> anyhow::Result<ExitStatus> {
+        let handle = self.clone();
+        cancel.status_lines(cmd, move |line| match parse_output_line(line) {
+            OutputLine::Text(text) => handle.build_output(&text),
+            OutputLine::Message(message, rendered) => {
+                handle.build_output(&rendered);
+                handle.update(|b| {
+                    if b.compiler_messages.len() < MAX_COMPILER_MESSAGES {
+                        b.compiler_messages.push(message);
+                    }
+                });
+            }
+            OutputLine::Ignored => {}
+        })
+    }
+
+    fn build_output(&self, text: &str) {
+        if !self.state.capture_output.load(Ordering::SeqCst) {
+            eprintln!("{text}");
+            return;
+        }
+        let name = self.name();
+        for line in strip_ansi(text).lines() {
+            self.state.push_tool_log(format!("[{name}] {line}"));
+        }
+    }
+
+    pub fn record_patch(&self, lib: String, for_build_id: Option<u64>, for_pid: Option<u32>) {
+        let builder = self.name();
+        let mut patches = self.state.patches.lock().unwrap();
+        if patches.len() == PATCH_HISTORY_LEN {
+            patches.pop_front();
+        }
+        patches.push_back(PatchRecord {
+            builder,
+            lib,
+            for_build_id,
+            for_pid,
+            sent_at_ms: unix_millis(),
+        });
+    }
+}
+
+/// What an output line of cargo or rustc is. Cargo is run with `--message-format json` and rustc
+/// with cargo's arguments, so diagnostics arrive as JSON.
+enum OutputLine {
+    Text(String),
+    /// A diagnostic and its rendered text, with colors
+    Message(CompilerMessage, String),
+    /// Other JSON messages, artifacts and the like
+    Ignored,
+}
+
+fn parse_output_line(line: &str) -> OutputLine {
+    if !line.starts_with('{') {
+        return OutputLine::Text(line.to_string());
+    }
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(line) else {
+        return OutputLine::Text(line.to_string());
+    };
+    // cargo wraps the messages of rustc
+    let message = match json.get("reason").and_then(|r| r.as_str()) {
+        Some("compiler-message") => &json["message"],
+        Some(_) => return OutputLine::Ignored,
+        None => &json,
+    };
+    let Some(rendered) = message.get("rendered").and_then(|r| r.as_str()) else {
+        return OutputLine::Ignored;
+    };
+    let str_field = |name| {
+        message
+            .get(name)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+    };
+    let location = message
+        .get("spans")
+        .and_then(|spans| spans.as_array())
+        .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true))
+        .map(|span| {
+            format!(
+                "{}:{}:{}",
+                span["file_name"].as_str().unwrap_or_default(),
+                span["line_start"],
+                span["column_start"]
+            )
+        });
+    OutputLine::Message(
+        CompilerMessage {
+            level: str_field("level").to_string(),
+            message: str_field("message").to_string(),
+            location,
+            rendered: strip_ansi(rendered.trim_end()),
+        },
+        rendered.trim_end().to_string(),
+    )
+}
+
+/// Removes the color codes the log panes and the control API can't show
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // `ESC [ <params> <letter>`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
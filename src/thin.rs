@@ -4,11 +4,17 @@ use std::sync::Arc;
 use std::time::{Instant, SystemTime};
 use std::{path::PathBuf, process::Command};
 
+use crate::cancel::CancelToken;
 use crate::context::Context;
+use crate::fingerprint::{SentObjects, objects_fingerprint};
 use crate::patch::{HotpatchModuleCache, create_undefined_symbol_stub};
+use crate::report;
+use crate::status::{BuildPhase, BuilderHandle};
+use crate::workspace::Members;
 use crate::{LinkerFlavor, RustcArgs};
+use anyhow::bail;
 use itertools::Itertools;
-use target_lexicon::OperatingSystem;
+use target_lexicon::{Architecture, OperatingSystem};
 
 fn thin_link_args(ctx: &Context, original_args: &[&str]) -> Vec<String> {
     let mut out_args = vec![];
@@ -49,6 +55,12 @@ fn thin_link_args(ctx: &Context, original_args: &[&str]) -> Vec<String> {
                 "--experimental-pic".to_string(),
             ]);
 
+            // memory64: the imported memory and table have to be 64-bit like the ones of the base
+            // module, wasm-ld assumes wasm32 without it
+            if ctx.triple.architecture == Architecture::Wasm64 {
+                out_args.push("-mwasm64".to_string());
+            }
+
             // retain exports so post-processing has hooks to work with
             for (idx, arg) in original_args.iter().enumerate() {
                 if *arg == "--export" {
@@ -161,6 +173,7 @@ fn thin_link_args(ctx: &Context, original_args: &[&str]) -> Vec<String> {
     out_args
 }
 
+#[allow(clippy::too_many_arguments)]
 fn write_patch(
     ctx: &Context,
     exe: &Path,
@@ -168,10 +181,15 @@ fn write_patch(
     // artifacts: &mut BuildArtifacts,
     cache: &Arc<HotpatchModuleCache>,
     rustc_args: &RustcArgs,
+    member_objects: &[PathBuf],
     time_start: SystemTime,
-) {
-    let raw_args = std::fs::read_to_string(ctx.link_args_file.path()).unwrap();
+    cancel: &CancelToken,
+    status: &BuilderHandle,
+) -> anyhow::Result<()> {
+    let raw_args = std::fs::read_to_string(ctx.link_args_file.path())?;
     let args = raw_args.lines().collect::<Vec<_>>();
+    report::keep_text(ctx, time_start, "link_args.txt", &raw_args);
+    report::keep_json(ctx, time_start, "rustc_args.json", rustc_args);
 
     // Extract out the incremental object files.
     //
@@ -232,6 +250,8 @@ fn write_patch(
         .sorted()
         .map(PathBuf::from)
         .collect::<Vec<_>>();
+    // workspace members recompiled since the fat build, their symbols replace the fat ones too
+    object_files.extend(member_objects.iter().cloned());
 
     // On non-wasm platforms, we generate a special shim object file which converts symbols from
     // fat binary into direct addresses from the running process.
@@ -247,13 +267,23 @@ fn write_patch(
     // Android apps can take a long time to open, and a hot patch might've been issued in the interim,
     // making this hotpatch a failure.
     if !ctx.is_wasm_or_wasi() {
+        status.set_phase(BuildPhase::Stub);
         let stub_bytes =
-            create_undefined_symbol_stub(cache, &object_files, &ctx.triple, aslr_reference)
-                .expect("failed to resolve patch symbols");
+            match create_undefined_symbol_stub(cache, &object_files, &ctx.triple, aslr_reference) {
+                Ok(stub_bytes) => stub_bytes,
+                Err(err) => {
+                    let err = anyhow::Error::from(err).context("failed to resolve patch symbols");
+                    report::keep_error(ctx, time_start, &err);
+                    for file in &object_files {
+                        report::keep_or_remove(ctx, time_start, file);
+                    }
+                    return Err(err);
+                }
+            };
 
         // Currently we're dropping stub.o in the exe dir, but should probably just move to a tempfile?
         let patch_file = exe.with_file_name("stub.o");
-        std::fs::write(&patch_file, stub_bytes).unwrap();
+        std::fs::write(&patch_file, stub_bytes)?;
         object_files.push(patch_file);
 
         // Add the dylibs/sos to the linker args
@@ -267,6 +297,7 @@ fn write_patch(
     }
 
     // And now we can run the linker with our new args
+    status.set_phase(BuildPhase::Linking);
     let linker = ctx.select_linker();
     let out_exe = ctx.patch_exe(time_start);
     let out_arg = match ctx.triple.operating_system {
@@ -296,20 +327,40 @@ fn write_patch(
     //
     // We dump its output directly into the patch exe location which is different than how rustc
     // does it since it uses llvm-objcopy into the `target/debug/` folder.
+    report::keep_text(
+        ctx,
+        time_start,
+        "linker_command.txt",
+        std::iter::once(linker.as_os_str())
+            .chain(out_args.iter().map(|arg| arg.as_os_str()))
+            .map(|arg| format!("{:?}", arg))
+            .join(" "),
+    );
     let mut linker_command = Command::new(linker);
     linker_command
         .args(out_args)
         .env_clear()
         .envs(rustc_args.envs.iter().map(|(k, v)| (k, v)));
-    let res = linker_command.output().unwrap();
-
-    if !res.stderr.is_empty() {
-        let errs = String::from_utf8_lossy(&res.stderr);
-        if !ctx.patch_exe(time_start).exists() || !res.status.success() {
-            tracing::error!("Failed to generate patch: {}", errs.trim());
-        } else {
-            tracing::trace!("Linker output during thin linking: {}", errs.trim());
+    let res = match cancel.output(&mut linker_command) {
+        Ok(res) => res,
+        Err(err) => {
+            // cancelled, nothing links these objects or the partial patch anymore
+            for file in &object_files {
+                _ = std::fs::remove_file(file);
+            }
+            _ = std::fs::remove_file(&out_exe);
+            return Err(err);
         }
+    };
+
+    let errs = String::from_utf8_lossy(&res.stderr);
+    let link_failed = !ctx.patch_exe(time_start).exists() || !res.status.success();
+    report::keep_text(ctx, time_start, "linker_output.txt", &res.stderr);
+    if !link_failed {
+        report::keep_copy(ctx, time_start, &ctx.patch_exe(time_start));
+    }
+    if !errs.is_empty() && !link_failed {
+        tracing::trace!("Linker output during thin linking: {}", errs.trim());
     }
 
     // For some really weird reason that I think is because of dlopen caching, future loads of the
@@ -331,27 +382,66 @@ fn write_patch(
     // self.write_index_html(&artifacts.assets)
     //     .context("Failed to write index.html")?;
 
-    // Clean up the temps manually
-    // todo: we might want to keep them around for debugging purposes
+    // Clean up the temps manually, with `--keep-temps` they are moved to the debug dir instead
     for file in object_files {
-        _ = std::fs::remove_file(file);
+        report::keep_or_remove(ctx, time_start, &file);
+    }
+
+    if link_failed {
+        let err = anyhow::anyhow!("Failed to generate patch: {}", errs.trim());
+        report::keep_error(ctx, time_start, &err);
+        return Err(err);
     }
+
+    Ok(())
 }
 
+/// Compiles and links a patch, returns `None` if clients already run the compiled objects, linking
+/// and sending such patch would be a waste of time. The objects of the patch are recorded as
+/// prepared in `objects`, the builder marks them as sent once the patch is.
+#[allow(clippy::too_many_arguments)]
 pub fn build_thin(
     ctx: &Context,
     rustc_args: &RustcArgs,
     aslr_reference: u64,
     cache: &Arc<HotpatchModuleCache>,
-) -> SystemTime {
+    members: &mut Members,
+    objects: &mut SentObjects,
+    cancel: &CancelToken,
+    status: &BuilderHandle,
+) -> anyhow::Result<Option<SystemTime>> {
     tracing::debug!("Thin build started");
     let time_start = SystemTime::now();
-    let mut cmd = build_thin_command(ctx, rustc_args);
-    let mut process = cmd.spawn().unwrap();
-    process.wait().unwrap();
+    let member_objects = members.rebuild(ctx, cancel, status)?;
+
+    let mut cmd = build_thin_command(ctx, rustc_args, members);
+    let exit_status = status.run(cancel, &mut cmd)?;
+    if !exit_status.success() {
+        bail!("rustc exited with {exit_status}");
+    }
+
+    let object_files = std::fs::read_to_string(ctx.link_args_file.path())?
+        .lines()
+        .filter(|arg| arg.ends_with(".rcgu.o"))
+        .map(PathBuf::from)
+        .chain(member_objects.iter().cloned())
+        .collect::<Vec<_>>();
+    let fingerprint = objects_fingerprint(&object_files);
+    status.metrics(|m| m.objects = Some(object_files.len()));
+    if objects.is_sent(fingerprint) {
+        for file in object_files {
+            _ = std::fs::remove_file(file);
+        }
+        tracing::debug!(
+            "Compiled code didn't change, skipping linking ({}s)",
+            time_start.elapsed().unwrap().as_secs_f32()
+        );
+        return Ok(None);
+    }
+
     let compiled_exe = ctx
         .target_triple_profile_dir()
-        .join(&ctx.final_binary_name());
+        .join(ctx.final_binary_name());
 
     let link_start = Instant::now();
     write_patch(
@@ -360,8 +450,12 @@ pub fn build_thin(
         aslr_reference,
         cache,
         rustc_args,
+        &member_objects,
         time_start,
-    );
+        cancel,
+        status,
+    )?;
+    objects.prepared(fingerprint);
     tracing::debug!(
         "Thin linking finished in {}s",
         link_start.elapsed().as_secs_f32()
@@ -372,14 +466,14 @@ pub fn build_thin(
         time_start.elapsed().unwrap().as_secs_f32()
     );
 
-    time_start
+    Ok(Some(time_start))
 }
 
-fn build_thin_command(ctx: &Context, rustc_args: &RustcArgs) -> Command {
+fn build_thin_command(ctx: &Context, rustc_args: &RustcArgs, members: &Members) -> Command {
     let mut cmd = Command::new("rustc");
     cmd.current_dir(&ctx.working_dir)
         .env_clear()
-        .args(rustc_args.args[1..].iter())
+        .args(members.redirect_externs(&rustc_args.args[1..]))
         .env_remove("RUSTC_WORKSPACE_WRAPPER")
         .env_remove("RUSTC_WRAPPER")
         .env_remove("DX_RUSTC")
@@ -393,7 +487,7 @@ fn build_thin_command(ctx: &Context, rustc_args: &RustcArgs) -> Command {
             ctx.link_err_file.path().canonicalize().unwrap(),
         )
         .env("DX_LINK_TRIPLE", ctx.triple.to_string())
-        .arg(format!("-Clinker=dx"));
+        .arg("-Clinker=dx");
 
     if ctx.is_wasm_or_wasi() {
         cmd.arg("-Crelocation-model=pic");
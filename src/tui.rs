@@ -0,0 +1,201 @@
+// Terminal UI showing what every builder is doing, the connected clients and the output of both this
+// tool and the launched binaries in separate panes. Enabled with `--tui`, otherwise the plain stdin
+// loop in main is used.
+
+use std::{
+    io::Write,
+    sync::{Arc, mpsc::Sender},
+    time::Duration,
+};
+
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+};
+
+use crate::{
+    builder::BuildCommand,
+    status::{BuildPhase, BuilderStatus, DevState, unix_millis},
+};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const HELP: &str = "r: thin build   R: fat rebuild   b: roll back last patch   q: quit";
+
+/// Runs until the user quits
+pub fn run(
+    command_tx: Sender<BuildCommand>,
+    clear_patches_tx: Sender<()>,
+    dev_state: Arc<DevState>,
+) -> std::io::Result<()> {
+    let mut terminal = ratatui::init();
+    let res = loop {
+        if let Err(err) = terminal.draw(|frame| draw(frame, &dev_state)) {
+            break Err(err);
+        }
+
+        match event::poll(REDRAW_INTERVAL) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(err) => break Err(err),
+        }
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(err) => break Err(err),
+        };
+        match key.code {
+            KeyCode::Char('r') => _ = command_tx.send(BuildCommand::Thin),
+            KeyCode::Char('R') => {
+                _ = clear_patches_tx.send(());
+                _ = command_tx.send(BuildCommand::FatRebuild);
+            }
+            KeyCode::Char('b') => _ = command_tx.send(BuildCommand::Rollback),
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+            _ => {}
+        }
+    };
+    ratatui::restore();
+    res
+}
+
+fn draw(frame: &mut Frame, dev_state: &DevState) {
+    let builders = dev_state.builders();
+    let clients = dev_state.clients();
+
+    let [builders_area, clients_area, logs_area, help_area] = Layout::vertical([
+        Constraint::Length(builders.len() as u16 + 3),
+        Constraint::Length(clients.len().max(1) as u16 + 2),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [tool_log_area, server_log_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(logs_area);
+
+    let rows = builders.iter().map(builder_row);
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(20),
+            Constraint::Length(26),
+            Constraint::Length(10),
+            Constraint::Percentage(40),
+            Constraint::Percentage(60),
+        ],
+    )
+    .header(
+        Row::new(["Builder", "Target", "Phase", "Last build", "Last error"])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(" Builders "));
+    frame.render_widget(table, builders_area);
+
+    let client_lines = if clients.is_empty() {
+        vec![Line::from("No clients connected")]
+    } else {
+        clients
+            .iter()
+            .map(|client| {
+                let aslr = client
+                    .aslr_reference
+                    .map(|aslr| format!("aslr {aslr:#x}"))
+                    .unwrap_or_else(|| "wasm".to_string());
+                let version = client
+                    .devtools_version
+                    .as_deref()
+                    .unwrap_or("unknown version");
+                let connected = unix_millis().saturating_sub(client.connected_at_ms) / 1000;
+                Line::from(format!(
+                    "#{} {} ({aslr}, {version}, connected {connected}s ago)",
+                    client.id, client.addr
+                ))
+            })
+            .collect()
+    };
+    frame.render_widget(
+        Paragraph::new(client_lines).block(Block::bordered().title(" Clients ")),
+        clients_area,
+    );
+
+    draw_log(frame, tool_log_area, " Build log ", &dev_state.tool_log());
+    draw_log(
+        frame,
+        server_log_area,
+        " Server output ",
+        &dev_state.server_log(),
+    );
+
+    frame.render_widget(
+        Paragraph::new(HELP).style(Style::default().fg(Color::DarkGray)),
+        help_area,
+    );
+}
+
+fn builder_row(builder: &BuilderStatus) -> Row<'static> {
+    let phase_color = match builder.phase {
+        BuildPhase::Idle => Color::Green,
+        _ => Color::Yellow,
+    };
+    let last_build = builder
+        .last_build
+        .as_ref()
+        .map(|build| {
+            let phases = build
+                .phases
+                .iter()
+                .map(|p| format!("{} {}ms", p.phase, p.elapsed_ms))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{:?} {}ms ({phases})", build.command, build.elapsed_ms)
+        })
+        .unwrap_or_default();
+    let last_error = builder
+        .last_error
+        .as_ref()
+        .map(|err| err.message.lines().next().unwrap_or_default().to_string())
+        .unwrap_or_default();
+
+    Row::new([
+        Line::from(builder.name.clone()),
+        Line::from(builder.target.clone()),
+        Line::from(builder.phase.to_string()).style(Style::default().fg(phase_color)),
+        Line::from(last_build),
+        Line::from(last_error).style(Style::default().fg(Color::Red)),
+    ])
+}
+
+/// Renders the tail of `lines` that fits in `area`
+fn draw_log(frame: &mut Frame, area: Rect, title: &str, lines: &[String]) {
+    let height = area.height.saturating_sub(2) as usize;
+    let lines = lines[lines.len().saturating_sub(height)..]
+        .iter()
+        .map(|line| Line::from(line.clone()))
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(title.to_string())),
+        area,
+    );
+}
+
+/// Sends tracing output to the build log pane instead of the terminal
+pub struct TuiLogWriter(pub Arc<DevState>);
+
+impl Write for TuiLogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for line in String::from_utf8_lossy(buf).lines() {
+            if !line.is_empty() {
+                self.0.push_tool_log(line.to_string());
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
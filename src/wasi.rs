@@ -0,0 +1,75 @@
+// WASI targets (`wasm32-wasip1`) running under a local runtime.
+//
+// The fat module is linked like a web build, without wasm-bindgen, and launched with `wasmtime` or
+// `wasmer` (`--wasi-runtime` or `wasi-runtime` in `[package.metadata.leptos-subsecond.run]`).
+//
+// Stock runtimes only launch the module and can't load a second module into a running instance,
+// so nothing could apply a patch: WASI targets are rebuilt fat and relaunched on every change
+// instead of being hot-patched.
+
+use std::path::{Path, PathBuf};
+
+use crate::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuntimeKind {
+    Wasmtime,
+    Wasmer,
+}
+
+/// Command line of the runtime launching a WASI module
+pub struct WasiRuntime {
+    program: PathBuf,
+    kind: RuntimeKind,
+}
+
+impl WasiRuntime {
+    pub fn new(ctx: &Context) -> Self {
+        let program = ctx
+            .run
+            .wasi_runtime
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("wasmtime"));
+        let kind = match program.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) if stem.contains("wasmer") => RuntimeKind::Wasmer,
+            _ => RuntimeKind::Wasmtime,
+        };
+        Self { program, kind }
+    }
+
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    /// Arguments running `module` with `cwd` preopened, runtimes don't pass the host environment to
+    /// the guest so `envs` are forwarded explicitly
+    pub fn args(
+        &self,
+        module: &Path,
+        cwd: &Path,
+        envs: &[(String, String)],
+        args: &[String],
+    ) -> Vec<String> {
+        let mut out = vec!["run".to_string()];
+        match self.kind {
+            RuntimeKind::Wasmtime => {
+                out.extend(["--dir".to_string(), format!("{}::/", cwd.display())]);
+                for (key, value) in envs {
+                    out.extend(["--env".to_string(), format!("{key}={value}")]);
+                }
+                out.push(module.display().to_string());
+                out.extend(args.iter().cloned());
+            }
+            RuntimeKind::Wasmer => {
+                out.extend(["--mapdir".to_string(), format!("/:{}", cwd.display())]);
+                for (key, value) in envs {
+                    out.extend(["--env".to_string(), format!("{key}={value}")]);
+                }
+                out.push(module.display().to_string());
+                out.push("--".to_string());
+                out.extend(args.iter().cloned());
+            }
+        }
+        out
+    }
+}
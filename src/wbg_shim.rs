@@ -0,0 +1,136 @@
+// JS glue for wasm-bindgen imports that are new in a patch.
+//
+// wasm-bindgen only runs on the fat build, so a patch calling a JS import the fat build never used
+// (a new `web_sys` method, a new `#[wasm_bindgen] extern` function) has nothing to link against.
+// `create_wasm_jump_table` moves such imports to the `__subsecond_wbg_shim__` import module and this
+// module generates a shim for them: wasm-bindgen is run on the unprocessed patch and the generated glue
+// is written next to the patch as `lib<name>-patch-<millis>.js`, ending with
+//
+// export function __subsecond_shim(env, app) -> { <import name>: function, ... }
+//
+// The hot-patch client imports the shim and passes the main module's exports (the `env` imports of the
+// patch) so the glue reads and writes the shared memory of the app.
+//
+// JS objects and closures passed to the patch live in the heap (or externref table) of the app's glue,
+// not in the one of the shim's glue. The fat build's glue publishes its helpers as
+// `globalThis.__subsecond_glue` (see `expose_helpers`), the client passes them as `app` and the shim
+// replaces its own helpers with them. A patch still needs a fat rebuild if it uses a helper the app's
+// glue doesn't have.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use wasm_bindgen_cli_support::Bindgen;
+
+use crate::patch::{PatchError, wbg_shim_imports};
+
+/// Helpers of the generated glue that hand out or look up objects in its heap or externref table
+const HEAP_HELPERS: &[&str] = &[
+    "getObject",
+    "addHeapObject",
+    "takeObject",
+    "dropObject",
+    "addToExternrefTable0",
+    "handleError",
+    "makeClosure",
+    "makeMutClosure",
+];
+
+/// Whether `glue` declares the helper `name`
+fn declares(glue: &str, name: &str) -> bool {
+    glue.contains(&format!("function {name}("))
+}
+
+/// Code appended to the glue of the fat build, publishing the helpers it declares for the shims
+pub fn expose_helpers(glue: &str) -> String {
+    let helpers = HEAP_HELPERS
+        .iter()
+        .filter(|helper| declares(glue, helper))
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("\nglobalThis.__subsecond_glue = {{ {helpers} }};\n")
+}
+
+/// Writes the shim for the new wasm-bindgen imports of the processed `patch`, `unprocessed` being the
+/// patch as linked and `app_glue` the glue of the fat build. Returns `None` if the patch has no new
+/// imports.
+pub fn write_shim(
+    unprocessed: &[u8],
+    patch: &Path,
+    app_glue: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    let names = wbg_shim_imports(patch)?;
+    if names.is_empty() {
+        return Ok(None);
+    }
+    tracing::info!(
+        "Patch uses {} new wasm-bindgen imports, generating JS glue",
+        names.len()
+    );
+
+    let input = tempfile::Builder::new().suffix(".wasm").tempfile()?;
+    std::fs::write(input.path(), unprocessed)?;
+    let output = Bindgen::new()
+        .keep_lld_exports(true)
+        .demangle(false)
+        .input_path(input.path())
+        .web(true)?
+        .generate_output()
+        .context("wasm-bindgen failed on the patch")?;
+    let glue = output.js();
+
+    let mut unsupported = vec![];
+    for name in &names {
+        let Some(function) = import_function(glue, name) else {
+            return Err(PatchError::UnshimmableImports(format!(
+                "wasm-bindgen generated no glue for {name}"
+            ))
+            .into());
+        };
+        let missing = HEAP_HELPERS
+            .iter()
+            .find(|helper| function.contains(&format!("{helper}(")) && !declares(app_glue, helper));
+        if let Some(helper) = missing {
+            unsupported.push(format!("{name} ({helper})"));
+        }
+    }
+    if !unsupported.is_empty() {
+        return Err(PatchError::UnshimmableImports(format!(
+            "New wasm-bindgen imports use helpers the app's glue doesn't have: {}",
+            unsupported.join(", ")
+        ))
+        .into());
+    }
+
+    // the helpers are function declarations, so they can be rebound to the ones of the app
+    let delegates = HEAP_HELPERS
+        .iter()
+        .filter(|helper| declares(glue, helper) && declares(app_glue, helper))
+        .map(|helper| format!("    {helper} = app.{helper};\n"))
+        .collect::<String>();
+
+    let exports = names
+        .iter()
+        .map(|name| format!("        {name}: wbg.{name},\n"))
+        .collect::<String>();
+    let shim = format!(
+        "{glue}\n\nexport function __subsecond_shim(env, app) {{\n    wasm = env;\n{delegates}    const wbg = __wbg_get_imports().wbg;\n    return {{\n{exports}    }};\n}}\n"
+    );
+
+    let path = patch.with_extension("js");
+    std::fs::write(&path, shim)?;
+    Ok(Some(path))
+}
+
+/// Body of `imports.wbg.<name> = ...` inside `__wbg_get_imports` of the generated glue
+fn import_function<'a>(glue: &'a str, name: &str) -> Option<&'a str> {
+    let start = glue.find(&format!("imports.wbg.{name} = "))?;
+    let rest = &glue[start..];
+    let end = rest[1..]
+        .find("imports.wbg.")
+        .map(|end| end + 1)
+        .or_else(|| rest.find("return imports;"))
+        .unwrap_or(rest.len());
+    Some(&rest[..end])
+}
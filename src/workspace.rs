@@ -0,0 +1,481 @@
+// Workspace members in patches.
+//
+// The `dx` rustc wrapper only records the invocation of the top-level crate, so the fat build runs
+// this binary as `RUSTC_WRAPPER` in front of it. Every library of a workspace member compiled for the
+// target gets its rustc invocation written to the capture dir, then `dx` runs as usual:
+//
+// target/<triple>/<profile>/subsecond-rustc/<binary>/<package>.json
+//
+// Thin builds replay the invocations of the members whose sources changed, and of the members
+// depending on them, before the top-level crate. The objects of their rlibs are linked into the patch
+// along with the ones of the top-level crate, symbols they use from unchanged crates are resolved like
+// any other symbol of the fat binary.
+//
+// Replayed invocations write to the CLI's own dir instead of cargo's deps dir, overwriting cargo's rlib
+// would make crates cargo compiles later find two versions of the member (E0460). The replays of
+// dependents and the thin rustc of the top-level crate get their `--extern`s pointed at the replayed
+// rlibs, and the dir as `-L dependency=`.
+//
+// target/<triple>/<profile>/subsecond-members/deps/      replayed rlibs
+// target/<triple>/<profile>/subsecond-members/objects/   their codegen units, linked into the patch
+//
+// A member recompiled by a thin build stays part of every later patch since patches always replace
+// code of the fat binary.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{cancel::CancelToken, context::Context, status::BuilderHandle};
+
+const CAPTURE_DIR_ENV: &str = "SUBSECOND_RUSTC_CAPTURE_DIR";
+const WORKSPACE_ROOT_ENV: &str = "SUBSECOND_WORKSPACE_ROOT";
+const INNER_WRAPPER_ENV: &str = "SUBSECOND_INNER_RUSTC_WRAPPER";
+
+/// Recorded rustc invocation of a workspace member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberRustc {
+    pub package: String,
+    pub crate_name: String,
+    /// rustc followed by its arguments
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+    pub cwd: PathBuf,
+    /// Millis since the epoch, cargo starts dependencies before their dependents
+    pub captured_at: u128,
+}
+
+impl MemberRustc {
+    fn arg_value(&self, flag: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .position(|arg| arg == flag)
+            .and_then(|idx| self.args.get(idx + 1))
+            .map(String::as_str)
+    }
+
+    fn codegen_value(&self, option: &str) -> Option<&str> {
+        let prefix = format!("{option}=");
+        self.args.iter().enumerate().find_map(|(idx, arg)| {
+            let value = match arg.strip_prefix("-C") {
+                Some("") => self.args.get(idx + 1)?.as_str(),
+                Some(value) => value,
+                None => return None,
+            };
+            value.strip_prefix(&prefix)
+        })
+    }
+
+    fn out_dir(&self) -> PathBuf {
+        self.cwd.join(self.arg_value("--out-dir").unwrap_or("."))
+    }
+
+    /// `lib<crate>-<hash>`, the file stem of the rlib and rmeta
+    fn output_stem(&self) -> String {
+        let extra = self.codegen_value("extra-filename").unwrap_or_default();
+        format!("lib{}{extra}", self.crate_name)
+    }
+
+    fn rlib(&self, out_dir: &Path) -> PathBuf {
+        out_dir.join(format!("{}.rlib", self.output_stem()))
+    }
+
+    fn dep_info(&self, out_dir: &Path) -> PathBuf {
+        let extra = self.codegen_value("extra-filename").unwrap_or_default();
+        out_dir.join(format!("{}{extra}.d", self.crate_name))
+    }
+
+    /// Whether a source file listed in the dep-info is newer than the rlib in `out_dir`, missing
+    /// files count as changed
+    fn is_stale(&self, out_dir: &Path) -> bool {
+        let Ok(built) = std::fs::metadata(self.rlib(out_dir)).and_then(|m| m.modified()) else {
+            return true;
+        };
+        let Ok(dep_info) = std::fs::read_to_string(self.dep_info(out_dir)) else {
+            return true;
+        };
+        dep_info_sources(&dep_info).iter().any(|source| {
+            std::fs::metadata(self.cwd.join(source))
+                .and_then(|m| m.modified())
+                .map(|modified| modified > built)
+                .unwrap_or(true)
+        })
+    }
+
+    fn depends_on(&self, other: &MemberRustc) -> bool {
+        let stem = other.output_stem();
+        self.args.windows(2).any(|pair| {
+            pair[0] == "--extern"
+                && pair[1]
+                    .split_once('=')
+                    .and_then(|(_, path)| Path::new(path).file_stem())
+                    .is_some_and(|file_stem| file_stem.to_string_lossy() == stem)
+        })
+    }
+}
+
+/// Source files of a rustc dep-info file, each of them has a `<path>:` line of its own
+fn dep_info_sources(dep_info: &str) -> Vec<PathBuf> {
+    dep_info
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.strip_suffix(':'))
+        .map(|path| PathBuf::from(path.replace("\\ ", " ")))
+        .collect()
+}
+
+pub fn capture_dir(ctx: &Context) -> PathBuf {
+    ctx.target_triple_profile_dir()
+        .join("subsecond-rustc")
+        .join(ctx.final_binary_name())
+}
+
+/// Envs making the fat build record the members, existing captures are kept and the members
+/// without one get their cargo fingerprint removed so they are compiled, and captured, again
+pub fn capture_envs(ctx: &Context, inner_wrapper: &str) -> Vec<(String, String)> {
+    let dir = capture_dir(ctx);
+    _ = std::fs::create_dir_all(&dir);
+
+    let (root, packages) = workspace_packages(ctx);
+    for package in packages {
+        if package.name != ctx.package && !dir.join(format!("{}.json", package.name)).exists() {
+            crate::fat::clean_fingerprint(ctx, &package.name);
+        }
+    }
+
+    let wrapper = std::env::current_exe().unwrap();
+    vec![
+        ("RUSTC_WRAPPER".to_string(), wrapper.display().to_string()),
+        (INNER_WRAPPER_ENV.to_string(), inner_wrapper.to_string()),
+        (CAPTURE_DIR_ENV.to_string(), dir.display().to_string()),
+        (WORKSPACE_ROOT_ENV.to_string(), root.display().to_string()),
+    ]
+}
+
+/// Package of the workspace, from `cargo metadata`
+#[derive(Debug, Clone)]
+pub struct WorkspacePackage {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub build_script: Option<PathBuf>,
+    /// Entry point of the proc-macro library, if the package is one
+    pub proc_macro: Option<PathBuf>,
+    /// Names of the binary targets
+    pub bins: Vec<String>,
+}
+
+/// Root and packages of the workspace of the project
+pub fn workspace_packages(ctx: &Context) -> (PathBuf, Vec<WorkspacePackage>) {
+    workspace_packages_in(&ctx.working_dir)
+}
+
+/// Root and packages of the workspace containing `dir`
+pub fn workspace_packages_in(dir: &Path) -> (PathBuf, Vec<WorkspacePackage>) {
+    let output = Command::new("cargo")
+        .arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version")
+        .arg("1")
+        .current_dir(dir)
+        .output();
+    let metadata = match output {
+        Ok(output) if output.status.success() => {
+            serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+        }
+        _ => {
+            tracing::warn!("cargo metadata failed, workspace members won't be tracked");
+            return (dir.to_path_buf(), vec![]);
+        }
+    };
+    let root = metadata["workspace_root"]
+        .as_str()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dir.to_path_buf());
+    let packages = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|package| {
+            let targets_of_kind = |kind: &str| {
+                package["targets"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|target| {
+                        target["kind"]
+                            .as_array()
+                            .is_some_and(|kinds| kinds.iter().any(|k| k == kind))
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let target_of_kind = |kind: &str| {
+                targets_of_kind(kind)
+                    .first()
+                    .and_then(|target| target["src_path"].as_str())
+                    .map(PathBuf::from)
+            };
+            Some(WorkspacePackage {
+                name: package["name"].as_str()?.to_string(),
+                manifest_path: PathBuf::from(package["manifest_path"].as_str()?),
+                build_script: target_of_kind("custom-build"),
+                proc_macro: target_of_kind("proc-macro"),
+                bins: targets_of_kind("bin")
+                    .into_iter()
+                    .filter_map(|target| target["name"].as_str().map(str::to_string))
+                    .collect(),
+            })
+        })
+        .collect();
+    (root, packages)
+}
+
+/// Entry point when cargo runs this binary as its rustc wrapper, returns the exit code
+pub fn run_as_rustc_wrapper() -> Option<i32> {
+    let capture_dir = std::env::var_os(CAPTURE_DIR_ENV)?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    if let Some(member) = member_invocation(&args) {
+        let path = Path::new(&capture_dir).join(format!("{}.json", member.package));
+        if let Err(err) = std::fs::write(&path, serde_json::to_vec(&member).unwrap()) {
+            eprintln!("Couldn't record rustc args of {}: {err}", member.package);
+        }
+    }
+
+    let inner = std::env::var(INNER_WRAPPER_ENV).unwrap_or_else(|_| "dx".to_string());
+    let status = Command::new(&inner).args(&args).status();
+    Some(match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(err) => {
+            eprintln!("Couldn't run rustc wrapper {inner}: {err}");
+            1
+        }
+    })
+}
+
+/// Libraries of workspace members compiled for the target, the top-level crate is recorded by `dx`
+fn member_invocation(args: &[String]) -> Option<MemberRustc> {
+    if std::env::var_os("CARGO_PRIMARY_PACKAGE").is_some() {
+        return None;
+    }
+    let root = std::env::var_os(WORKSPACE_ROOT_ENV)?;
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR")?;
+    if !Path::new(&manifest_dir).starts_with(&root) {
+        return None;
+    }
+
+    let value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|idx| args.get(idx + 1))
+    };
+    // build scripts and proc macros are compiled for the host without `--target`
+    value("--target")?;
+    if !matches!(value("--crate-type")?.as_str(), "lib" | "rlib") {
+        return None;
+    }
+
+    let envs = std::env::vars()
+        .filter(|(key, _)| {
+            !matches!(
+                key.as_str(),
+                "RUSTC_WRAPPER" | "RUSTC_WORKSPACE_WRAPPER" | "DX_RUSTC"
+            ) && !key.starts_with("SUBSECOND_")
+        })
+        .collect();
+    Some(MemberRustc {
+        package: std::env::var("CARGO_PKG_NAME").ok()?,
+        crate_name: value("--crate-name")?.clone(),
+        args: args.to_vec(),
+        envs,
+        cwd: std::env::current_dir().ok()?,
+        captured_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis(),
+    })
+}
+
+/// Members the thin builds since the last fat build know about
+#[derive(Debug, Default)]
+pub struct Members {
+    /// Captured by fat builds, dependencies first
+    captured: Vec<MemberRustc>,
+    /// Recompiled since the last fat build
+    patched: BTreeSet<String>,
+    /// Where replayed invocations write, see the module docs
+    dir: PathBuf,
+}
+
+impl Members {
+    /// Loads the captures after a fat build, cargo's rlibs are current again
+    pub fn load(ctx: &Context) -> Self {
+        let dir = ctx.target_triple_profile_dir().join("subsecond-members");
+        _ = std::fs::remove_dir_all(&dir);
+        Self {
+            captured: load_captures(ctx),
+            patched: BTreeSet::new(),
+            dir,
+        }
+    }
+
+    fn deps_dir(&self) -> PathBuf {
+        self.dir.join("deps")
+    }
+
+    /// Where the current rlib of `member` is
+    fn out_dir(&self, member: &MemberRustc) -> PathBuf {
+        if self.patched.contains(&member.package) {
+            self.deps_dir()
+        } else {
+            member.out_dir()
+        }
+    }
+
+    /// Rustc arguments using the replayed rlibs of the members patched so far instead of cargo's
+    pub fn redirect_externs(&self, args: &[String]) -> Vec<String> {
+        if self.patched.is_empty() {
+            return args.to_vec();
+        }
+        let deps_dir = self.deps_dir();
+        let mut out = Vec::with_capacity(args.len() + 2);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            out.push(arg.clone());
+            if arg != "--extern" {
+                continue;
+            }
+            let Some(value) = args.next() else {
+                break;
+            };
+            let replayed = value.split_once('=').and_then(|(name, path)| {
+                let file_name = Path::new(path).file_name()?;
+                let stem = Path::new(file_name).file_stem()?.to_string_lossy();
+                self.captured
+                    .iter()
+                    .any(|member| {
+                        self.patched.contains(&member.package) && member.output_stem() == stem
+                    })
+                    .then(|| format!("{name}={}", deps_dir.join(file_name).display()))
+            });
+            out.push(replayed.unwrap_or_else(|| value.clone()));
+        }
+        // crates found through the metadata of others, e.g. a patched dependency of a dependency
+        out.push("-L".to_string());
+        out.push(format!("dependency={}", deps_dir.display()));
+        out
+    }
+}
+
+fn load_captures(ctx: &Context) -> Vec<MemberRustc> {
+    let Ok(entries) = std::fs::read_dir(capture_dir(ctx)) else {
+        return vec![];
+    };
+    let mut members = entries
+        .flatten()
+        .filter_map(|entry| std::fs::read(entry.path()).ok())
+        .filter_map(|bytes| serde_json::from_slice::<MemberRustc>(&bytes).ok())
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| {
+        a.captured_at
+            .cmp(&b.captured_at)
+            .then_with(|| a.package.cmp(&b.package))
+    });
+
+    // captures of earlier fat builds can be older than the ones of their dependencies
+    let mut ordered: Vec<MemberRustc> = Vec::with_capacity(members.len());
+    while !members.is_empty() {
+        let next = members
+            .iter()
+            .position(|member| {
+                !members
+                    .iter()
+                    .any(|other| other.package != member.package && member.depends_on(other))
+            })
+            .unwrap_or(0);
+        ordered.push(members.remove(next));
+    }
+    ordered
+}
+
+impl Members {
+    /// Recompiles the changed members and their dependents, then extracts the objects of every
+    /// member recompiled since the last fat build
+    pub fn rebuild(
+        &mut self,
+        ctx: &Context,
+        cancel: &CancelToken,
+        builder: &BuilderHandle,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut rebuild: Vec<MemberRustc> = vec![];
+        for member in &self.captured {
+            if member.is_stale(&self.out_dir(member))
+                || rebuild.iter().any(|dep| member.depends_on(dep))
+            {
+                rebuild.push(member.clone());
+            }
+        }
+
+        let deps_dir = self.deps_dir();
+        std::fs::create_dir_all(&deps_dir)?;
+        for member in rebuild {
+            tracing::debug!("Recompiling workspace member {}", member.package);
+            // dependencies come first, their replayed rlibs already exist
+            let mut args = self.redirect_externs(&member.args[1..]);
+            if let Some(idx) = args.iter().position(|arg| arg == "--out-dir") {
+                args[idx + 1] = deps_dir.display().to_string();
+            } else {
+                args.extend(["--out-dir".to_string(), deps_dir.display().to_string()]);
+            }
+            let mut cmd = Command::new(&member.args[0]);
+            cmd.current_dir(&member.cwd)
+                .env_clear()
+                .args(args)
+                .envs(member.envs.iter().cloned());
+            if ctx.is_wasm_or_wasi() {
+                cmd.arg("-Crelocation-model=pic");
+            }
+            let status = builder.run(cancel, &mut cmd)?;
+            if !status.success() {
+                bail!("rustc exited with {status} compiling {}", member.package);
+            }
+            self.patched.insert(member.package.clone());
+        }
+
+        let objects_dir = self.dir.join("objects");
+        _ = std::fs::remove_dir_all(&objects_dir);
+        let mut objects = vec![];
+        for member in &self.captured {
+            if self.patched.contains(&member.package) {
+                objects.extend(extract_objects(
+                    &member.rlib(&deps_dir),
+                    &objects_dir.join(&member.crate_name),
+                )?);
+            }
+        }
+        Ok(objects)
+    }
+}
+
+/// Writes the codegen units of an rlib into `dir`
+fn extract_objects(rlib: &Path, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let mut archive = ar::Archive::new(std::fs::File::open(rlib)?);
+    let mut objects = vec![];
+    while let Some(entry) = archive.next_entry() {
+        let mut entry = entry?;
+        let name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        if !name.ends_with(".rcgu.o") {
+            continue;
+        }
+        let path = dir.join(&name);
+        std::io::copy(&mut entry, &mut std::fs::File::create(&path)?)?;
+        objects.push(path);
+    }
+    Ok(objects)
+}
@@ -9,13 +9,23 @@ use std::{
 
 use dioxus_devtools::DevserverMsg;
 use multiqueue::BroadcastReceiver;
-use tungstenite::handshake::server::{Request, Response};
+use tungstenite::{
+    handshake::server::{Request, Response},
+    http::HeaderValue,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+
+use crate::{
+    protocol::{DEVTOOLS_VERSION, Handshake, SUBSECOND_TYPES_VERSION, is_for_build},
+    status::DevState,
+};
 
 pub struct HotPatchServer {
     addr: String,
     patch_receiver: BroadcastReceiver<DevserverMsg>,
     aslr_reference: Arc<AtomicU64>,
     clear_patches_rx: Receiver<()>,
+    dev_state: Arc<DevState>,
 }
 
 impl HotPatchServer {
@@ -24,12 +34,14 @@ impl HotPatchServer {
         patch_receiver: BroadcastReceiver<DevserverMsg>,
         aslr_reference: Arc<AtomicU64>,
         clear_patches_rx: Receiver<()>,
+        dev_state: Arc<DevState>,
     ) -> Self {
         Self {
             addr: addr.to_string(),
             patch_receiver,
             aslr_reference,
             clear_patches_rx,
+            dev_state,
         }
     }
 
@@ -49,60 +61,126 @@ impl HotPatchServer {
             if let Ok(stream) = new_stream {
                 let channel = self.patch_receiver.add_stream();
                 let aslr_reference = Arc::clone(&self.aslr_reference);
-                std::thread::spawn(move || Self::client_loop(stream, aslr_reference, channel));
+                let dev_state = Arc::clone(&self.dev_state);
+                std::thread::spawn(move || {
+                    Self::client_loop(stream, aslr_reference, channel, dev_state)
+                });
             }
         }
     }
 
     pub fn clear_patches_if_command_received(&mut self) {
-        if let Ok(_) = self.clear_patches_rx.try_recv() {
-            while let Ok(_) = self.patch_receiver.try_recv() {}
+        if self.clear_patches_rx.try_recv().is_ok() {
+            while self.patch_receiver.try_recv().is_ok() {}
         }
     }
 
+    // the handshake callback has to return tungstenite's `ErrorResponse`
+    #[allow(clippy::result_large_err)]
     pub fn client_loop(
         stream: TcpStream,
         aslr_reference: Arc<AtomicU64>,
         patch_channel: BroadcastReceiver<DevserverMsg>,
+        dev_state: Arc<DevState>,
     ) {
+        let addr = stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        let mut handshake = Handshake::default();
+        let build_ids = dev_state
+            .build_ids()
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
         let mut websocket =
-            tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+            tungstenite::accept_hdr(stream, |request: &Request, mut response: Response| {
                 if let Some(query) = request.uri().query() {
-                    let split = query.split("&");
-                    // a little bit ugly hack to get aslr back to the builder
-                    // TODO: find another way to get aslr reference back
-                    for s in split {
-                        if let Some(aslr_str) = s.strip_prefix("aslr_reference=") {
-                            if let Ok(new_aslr_reference) = aslr_str.parse() {
-                                if new_aslr_reference != 0 {
-                                    aslr_reference.store(
-                                        new_aslr_reference,
-                                        std::sync::atomic::Ordering::SeqCst,
-                                    );
-                                }
-                            }
-                            break;
-                        }
-                    }
+                    handshake = Handshake::parse(query);
+                }
+                let headers = response.headers_mut();
+                headers.insert(
+                    "x-devtools-version",
+                    HeaderValue::from_static(DEVTOOLS_VERSION),
+                );
+                headers.insert(
+                    "x-subsecond-types",
+                    HeaderValue::from_static(SUBSECOND_TYPES_VERSION),
+                );
+                if let Ok(build_ids) = HeaderValue::from_str(&build_ids) {
+                    headers.insert("x-build-ids", build_ids);
                 }
-                Ok(Response::from(response))
+                Ok(response)
             })
             .unwrap();
+
+        let announced_version = handshake.devtools_version.is_some();
+        let handshake = handshake.or_app_versions(&dev_state.app_versions());
+        if let Some(reason) = handshake.incompatibility() {
+            tracing::error!("Refusing to send patches to {addr}: {reason}");
+            _ = websocket.close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: reason.into(),
+            }));
+            _ = websocket.flush();
+            return;
+        }
+        if !announced_version && handshake.devtools_version.is_none() {
+            tracing::warn!(
+                "Hot-patch client {addr} didn't announce its devtools version, patches may be misapplied if it isn't {DEVTOOLS_VERSION}"
+            );
+        }
+
+        // a little bit ugly hack to get aslr back to the builder
+        // TODO: find another way to get aslr reference back
+        if let Some(new_aslr_reference) = handshake.aslr_reference.filter(|aslr| *aslr != 0) {
+            aslr_reference.store(new_aslr_reference, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        let client_id = dev_state.add_client(
+            addr.clone(),
+            handshake.aslr_reference,
+            handshake.devtools_version.clone(),
+            handshake.build_id,
+        );
         tracing::debug!("New hot-patch client connected");
 
+        // reads time out so that closed connections are noticed without waiting for a patch to send
+        _ = websocket
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_millis(50)));
+
         loop {
-            // this check I think do nothing to prevent trying to write a closed socket
-            // I got a panic once in a thread, but currently don't care because thats not a main thread
             if !websocket.can_write() {
                 break;
             }
+            match websocket.read() {
+                Ok(tungstenite::Message::Close(_)) => break,
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) => {}
+                Err(_) => break,
+            }
             if let Ok(msg) = patch_channel.try_recv() {
+                if !is_for_build(&msg, handshake.build_id) {
+                    tracing::debug!("Dropping patch for another build than {addr} is running");
+                    continue;
+                }
                 let serialized = serde_json::to_string(&msg).unwrap();
-                websocket
+                if websocket
                     .send(tungstenite::Message::Text(serialized.into()))
-                    .unwrap();
+                    .is_err()
+                {
+                    break;
+                }
             }
-            std::thread::sleep(Duration::from_millis(50));
         }
+
+        dev_state.remove_client(client_id);
+        tracing::debug!("Hot-patch client disconnected");
     }
 }